@@ -0,0 +1 @@
+drop table load_runs;
@@ -0,0 +1,8 @@
+CREATE TABLE load_runs (
+    id SERIAL PRIMARY KEY,
+    started_at timestamptz not null default now(),
+    finished_at timestamptz,
+    rows_inserted bigint not null default 0,
+    input_file text not null,
+    input_sha256 text not null,
+    status text not null);
@@ -0,0 +1,2 @@
+DROP MATERIALIZED VIEW IF EXISTS major_cities;
+ALTER TABLE cities DROP COLUMN raw;
@@ -0,0 +1,3 @@
+DROP MATERIALIZED VIEW IF EXISTS major_cities;
+UPDATE cities SET region = '' WHERE region IS NULL;
+ALTER TABLE cities ALTER COLUMN region SET NOT NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE cities ALTER COLUMN region DROP NOT NULL;
+UPDATE cities SET region = NULL WHERE trim(region) = '';
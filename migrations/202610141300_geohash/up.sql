@@ -0,0 +1,5 @@
+ALTER TABLE cities ADD COLUMN geohash text;
+
+-- text_pattern_ops serves LIKE 'prefix%' regardless of the database collation
+CREATE INDEX cities_geohash_idx
+  ON cities (geohash text_pattern_ops);
@@ -0,0 +1 @@
+ALTER TABLE cities ADD COLUMN display_name text;
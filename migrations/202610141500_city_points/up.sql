@@ -0,0 +1,12 @@
+CREATE TABLE city_points (
+    id SERIAL PRIMARY KEY,
+    city_id integer not null REFERENCES cities (id) ON DELETE CASCADE,
+    kind text not null,
+    location geometry(Point, 4326) not null);
+
+CREATE INDEX city_points_city_id_idx
+  ON city_points (city_id);
+
+CREATE INDEX city_points_location_idx
+  ON city_points
+  USING GIST (location);
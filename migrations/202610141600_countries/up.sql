@@ -0,0 +1,9 @@
+CREATE TABLE countries (
+    id SERIAL PRIMARY KEY,
+    name text not null,
+    iso_code text,
+    boundary geometry(MultiPolygon, 4326) not null);
+
+CREATE INDEX countries_boundary_idx
+  ON countries
+  USING GIST (boundary);
@@ -0,0 +1,4 @@
+DROP MATERIALIZED VIEW IF EXISTS major_cities;
+ALTER TABLE city_points ALTER COLUMN city_id TYPE integer;
+ALTER SEQUENCE cities_id_seq AS integer;
+ALTER TABLE cities ALTER COLUMN id TYPE integer;
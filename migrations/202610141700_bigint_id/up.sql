@@ -0,0 +1,5 @@
+-- the view depends on cities.id, MajorCities recreates it
+DROP MATERIALIZED VIEW IF EXISTS major_cities;
+ALTER TABLE cities ALTER COLUMN id TYPE bigint;
+ALTER SEQUENCE cities_id_seq AS bigint;
+ALTER TABLE city_points ALTER COLUMN city_id TYPE bigint;
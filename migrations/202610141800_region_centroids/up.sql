@@ -0,0 +1,9 @@
+CREATE TABLE region_centroids (
+    id SERIAL PRIMARY KEY,
+    country text not null,
+    region text,
+    cities bigint not null,
+    centroid geometry(Point, 4326) not null);
+
+CREATE INDEX region_centroids_country_region_idx
+  ON region_centroids (country, region);
@@ -0,0 +1,6 @@
+-- rows loaded before this migration get the time it ran, so an incremental export sends
+-- them once more rather than missing them
+ALTER TABLE cities ADD COLUMN loaded_at timestamptz NOT NULL DEFAULT now();
+
+CREATE INDEX cities_loaded_at_idx
+  ON cities (loaded_at);
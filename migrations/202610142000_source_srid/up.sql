@@ -0,0 +1,2 @@
+-- SRID of the input coordinates, NULL for rows loaded before it was recorded
+ALTER TABLE cities ADD COLUMN srid integer;
@@ -0,0 +1,3 @@
+DROP MATERIALIZED VIEW IF EXISTS major_cities;
+ALTER TABLE cities DROP COLUMN latitude;
+ALTER TABLE cities DROP COLUMN longitude;
@@ -0,0 +1,8 @@
+-- plain coordinates for clients without PostGIS, only filled by Upload --with-latlon
+ALTER TABLE cities ADD COLUMN latitude double precision;
+ALTER TABLE cities ADD COLUMN longitude double precision;
+
+-- partial, so loads without --with-latlon don't pay for it
+CREATE INDEX cities_latitude_longitude_idx
+  ON cities (latitude, longitude)
+  WHERE latitude IS NOT NULL;
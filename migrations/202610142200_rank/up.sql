@@ -0,0 +1,5 @@
+-- precomputed sort key of "important cities" queries, see Upload --compute-rank
+ALTER TABLE cities ADD COLUMN rank double precision;
+
+CREATE INDEX cities_rank_idx
+  ON cities (rank DESC NULLS LAST);
@@ -0,0 +1,132 @@
+//! Data quality measures of the loaded cities, to spot sources whose coordinates were
+//! rounded or snapped to a grid before they undermine nearest city queries.
+
+use std::collections::BTreeMap;
+
+use clap::Subcommand;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    sql_types::{Float8, Int8},
+    PgConnection, QueryableByName, RunQueryDsl,
+};
+
+use crate::log_query;
+
+/// Meters of a degree of latitude, the ground resolution of a coordinate with no decimals.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+#[derive(Subcommand)]
+pub(crate) enum AnalyzeCheck {
+    /// Print the decimal places of a random sample of coordinates and count the cities
+    /// sharing their exact coordinates with another city
+    Precision {
+        /// Number of cities sampled for the decimal places
+        #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(i64).range(1..))]
+        sample_size: i64,
+    },
+}
+
+pub(crate) fn run(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    check: AnalyzeCheck,
+    geometry_column: &str,
+) {
+    match check {
+        AnalyzeCheck::Precision { sample_size } => precision(c_pool, sample_size, geometry_column),
+    }
+}
+
+#[derive(QueryableByName)]
+struct Coordinates {
+    #[diesel(sql_type = Float8)]
+    lat: f64,
+    #[diesel(sql_type = Float8)]
+    lon: f64,
+}
+
+#[derive(QueryableByName)]
+struct Duplicates {
+    #[diesel(sql_type = Int8)]
+    cities: i64,
+    #[diesel(sql_type = Int8)]
+    points: i64,
+}
+
+fn precision(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    sample_size: i64,
+    geometry_column: &str,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let query = diesel::sql_query(format!(
+        "SELECT ST_Y(\"{g}\") AS lat, ST_X(\"{g}\") AS lon FROM cities \
+         WHERE \"{g}\" IS NOT NULL ORDER BY random() LIMIT $1",
+        g = geometry_column
+    ))
+    .bind::<Int8, _>(sample_size);
+    log_query(&query);
+    let sample: Vec<Coordinates> = query.load(&mut conn).expect("can't sample cities");
+    if sample.is_empty() {
+        println!("no cities");
+        return;
+    }
+    // the precision of a city is the one of its more precise coordinate, a longitude of
+    // 2.35 next to a latitude of 48.8566 is a rounding of the longitude's trailing zeros
+    let mut places: Vec<usize> = sample
+        .iter()
+        .map(|c| decimal_places(c.lat).max(decimal_places(c.lon)))
+        .collect();
+    let mut histogram = BTreeMap::new();
+    for &p in &places {
+        *histogram.entry(p).or_insert(0) += 1;
+    }
+    println!("sampled {} cities", sample.len());
+    println!("{:<16}{:>10}{:>8}", "decimal places", "cities", "share");
+    for (p, count) in &histogram {
+        println!(
+            "{:<16}{:>10}{:>7.1}%",
+            p,
+            count,
+            *count as f64 * 100.0 / sample.len() as f64
+        );
+    }
+    let median = places.len() / 2;
+    let typical = *places.select_nth_unstable(median).1;
+    println!(
+        "typical precision {} decimal places, about {} on the ground",
+        typical,
+        format_meters(METERS_PER_DEGREE / 10f64.powi(typical as i32))
+    );
+
+    let query = diesel::sql_query(format!(
+        "SELECT COALESCE(sum(n), 0)::int8 AS cities, count(*) AS points FROM (\
+           SELECT count(*) AS n FROM cities WHERE \"{g}\" IS NOT NULL \
+           GROUP BY ST_X(\"{g}\"), ST_Y(\"{g}\") HAVING count(*) > 1\
+         ) shared",
+        g = geometry_column
+    ));
+    log_query(&query);
+    let duplicates: Duplicates = query
+        .get_result(&mut conn)
+        .expect("can't count duplicate coordinates");
+    println!(
+        "exact duplicate coordinates: {} cities at {} points",
+        duplicates.cities, duplicates.points
+    );
+}
+
+/// Digits after the decimal point of the shortest representation of `x`, which is what
+/// the source wrote unless the coordinate was transformed on the way.
+fn decimal_places(x: f64) -> usize {
+    x.to_string().split_once('.').map_or(0, |(_, f)| f.len())
+}
+
+fn format_meters(meters: f64) -> String {
+    if meters >= 1000.0 {
+        format!("{:.1} km", meters / 1000.0)
+    } else if meters >= 1.0 {
+        format!("{:.0} m", meters)
+    } else {
+        format!("{:.2} m", meters)
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+
+use csv::StringRecord;
+
+/// Fields of `CityRecord` in the order of the worldcitiespop layout, used for files without
+/// a header.
+pub const FIELDS: [&str; 7] = [
+    "country",
+    "city",
+    "accent_city",
+    "region",
+    "latitude",
+    "longitude",
+    "population",
+];
+
+/// Fields which must have a column, region and population may be missing.
+const REQUIRED: [&str; 5] = ["country", "city", "accent_city", "latitude", "longitude"];
+
+/// Header names recognized for every field, compared after `normalize`.
+const SYNONYMS: [(&str, &[&str]); 7] = [
+    ("country", &["country", "countrycode", "cc", "iso2"]),
+    ("city", &["city", "name", "cityname", "asciiname", "town"]),
+    (
+        "accent_city",
+        &["accentcity", "accentname", "displayname", "localname"],
+    ),
+    (
+        "region",
+        &["region", "state", "province", "admin1", "admin1code"],
+    ),
+    ("latitude", &["latitude", "lat", "y"]),
+    ("longitude", &["longitude", "lon", "lng", "long", "x"]),
+    ("population", &["population", "pop", "inhabitants"]),
+];
+
+/// Header of the input translated to `CityRecord` field names, so rows can be deserialized
+/// whatever the file calls its columns.
+pub struct ColumnMapping {
+    /// Header used to deserialize rows, recognized columns renamed to their field.
+    pub fields: StringRecord,
+    /// Header as it is in the file, `None` if the file has none.
+    pub original: Option<StringRecord>,
+}
+
+impl ColumnMapping {
+    /// Columns by position, for files without a header.
+    pub fn positional() -> Self {
+        ColumnMapping {
+            fields: StringRecord::from(FIELDS.to_vec()),
+            original: None,
+        }
+    }
+
+    /// Maps the column names of the header row. Returns `None` if not a single name is
+    /// recognized, which means the row is data rather than a header.
+    pub fn detect(header: &StringRecord) -> Option<Result<Self, String>> {
+        let mut fields = Vec::with_capacity(header.len());
+        for name in header {
+            let name = normalize(name);
+            let field = SYNONYMS
+                .iter()
+                .find(|(_, synonyms)| synonyms.contains(&name.as_str()))
+                .map(|(field, _)| *field)
+                // a second column for the same field is ignored like any unknown one
+                .filter(|field| !fields.contains(field));
+            fields.push(field.unwrap_or(""));
+        }
+        if fields.iter().all(|f| f.is_empty()) {
+            return None;
+        }
+        let missing: Vec<_> = REQUIRED
+            .iter()
+            .filter(|f| !fields.contains(f))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Some(Err(format!(
+                "header has no column for {}",
+                missing.join(", ")
+            )));
+        }
+        Some(Ok(ColumnMapping {
+            fields: StringRecord::from(fields),
+            original: Some(header.clone()),
+        }))
+    }
+
+    /// Position of the first column called `name` which isn't mapped to a field, so a
+    /// repeated "latitude" column can be told apart from the one of the location.
+    pub fn unmapped_column(&self, name: &str) -> Option<usize> {
+        self.original
+            .as_ref()?
+            .iter()
+            .zip(self.fields.iter())
+            .position(|(column, field)| column.trim() == name && field.is_empty())
+    }
+
+    /// Header names for raw JSON of a row.
+    pub fn raw_names(&self) -> &StringRecord {
+        self.original.as_ref().unwrap_or(&self.fields)
+    }
+}
+
+impl fmt::Display for ColumnMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let original = match &self.original {
+            Some(original) => original,
+            None => {
+                return write!(
+                    f,
+                    "no header, columns by position: {}",
+                    self.fields.iter().collect::<Vec<_>>().join(", ")
+                )
+            }
+        };
+        let columns: Vec<_> = original
+            .iter()
+            .zip(self.fields.iter())
+            .map(|(name, field)| match field {
+                "" => format!("{} (ignored)", name),
+                field => format!("{} -> {}", name, field),
+            })
+            .collect();
+        write!(f, "columns: {}", columns.join(", "))
+    }
+}
+
+/// Lower case without spaces and punctuation, "Accent City" and "accent_city" are the same.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
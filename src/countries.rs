@@ -0,0 +1,160 @@
+//! Country boundaries loaded from GeoJSON, for reverse geocoding a point to its country
+//! rather than to the nearest city.
+
+use std::{collections::HashMap, io::BufReader, path::Path, process};
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    sql_types::{Float8, Nullable, Text},
+    Connection, PgConnection, QueryableByName, RunQueryDsl,
+};
+use serde_json::Value;
+
+use crate::{log_query, open_input, run_migration};
+
+/// Replaces the countries with the Polygon and MultiPolygon features of a GeoJSON
+/// FeatureCollection, other geometries are skipped.
+pub fn upload(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    input: &Path,
+    name_property: &str,
+    code_property: &str,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    run_migration(&mut conn);
+    let collection: Value =
+        serde_json::from_reader(BufReader::new(open_input(input, false, 64 * 1024)))
+            .unwrap_or_else(|e| panic!("can't parse {}: {}", input.display(), e));
+    let features = match collection["features"].as_array() {
+        Some(features) => features,
+        None => {
+            eprintln!("{} is not a GeoJSON FeatureCollection", input.display());
+            process::exit(1);
+        }
+    };
+    let mut skipped = 0;
+    let inserted = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::sql_query("DELETE FROM countries").execute(conn)?;
+            let mut inserted = 0;
+            for (i, feature) in features.iter().enumerate() {
+                let geometry = &feature["geometry"];
+                if !matches!(geometry["type"].as_str(), Some("Polygon" | "MultiPolygon")) {
+                    skipped += 1;
+                    continue;
+                }
+                let properties = &feature["properties"];
+                let name = match properties[name_property].as_str() {
+                    Some(name) => name,
+                    None => {
+                        eprintln!(
+                            "feature {} has no \"{}\" property, see --name-property",
+                            i, name_property
+                        );
+                        process::exit(1);
+                    }
+                };
+                // GeoJSON coordinates are always WGS 84
+                diesel::sql_query(
+                    "INSERT INTO countries (name, iso_code, boundary) \
+                     VALUES ($1, $2, ST_Multi(ST_SetSRID(ST_GeomFromGeoJSON($3), 4326)))",
+                )
+                .bind::<Text, _>(name)
+                .bind::<Nullable<Text>, _>(properties[code_property].as_str())
+                .bind::<Text, _>(geometry.to_string())
+                .execute(conn)?;
+                inserted += 1;
+            }
+            Ok(inserted)
+        })
+        .expect("can't load countries");
+    println!(
+        "loaded {} countries, skipped {} features without polygons",
+        inserted, skipped
+    );
+}
+
+#[derive(QueryableByName)]
+struct Country {
+    #[diesel(sql_type = Text)]
+    name: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    iso_code: Option<String>,
+}
+
+/// Prints the countries whose boundary contains the point, usually one, more where
+/// boundaries overlap. Returns the process exit code, 1 if no country contains it.
+pub fn country_of(c_pool: &Pool<ConnectionManager<PgConnection>>, lat: f64, lon: f64) -> i32 {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let query = diesel::sql_query(
+        "SELECT name, iso_code FROM countries \
+         WHERE ST_Contains(boundary, ST_SetSRID(ST_MakePoint($1, $2), 4326)) \
+         ORDER BY name",
+    )
+    .bind::<Float8, _>(lon)
+    .bind::<Float8, _>(lat);
+    log_query(&query);
+    let countries: Vec<Country> = query.load(&mut conn).expect("can't query countries");
+    if countries.is_empty() {
+        println!("no country contains {},{}", lat, lon);
+        return 1;
+    }
+    for country in countries {
+        match country.iso_code {
+            Some(code) => println!("{} ({})", country.name, code),
+            None => println!("{}", country.name),
+        }
+    }
+    0
+}
+
+#[derive(QueryableByName)]
+struct CountryBox {
+    #[diesel(sql_type = Text)]
+    code: String,
+    #[diesel(sql_type = Float8)]
+    min_lon: f64,
+    #[diesel(sql_type = Float8)]
+    min_lat: f64,
+    #[diesel(sql_type = Float8)]
+    max_lon: f64,
+    #[diesel(sql_type = Float8)]
+    max_lat: f64,
+}
+
+/// Bounding boxes of the country boundaries by lower case ISO code, a coarse check that a
+/// city is roughly where its country is. Boxes of countries across the antimeridian span
+/// nearly all longitudes, so they only check the latitude.
+pub struct CountryBoxes(HashMap<String, CountryBox>);
+
+impl CountryBoxes {
+    /// Boxes of the countries with an ISO code, exits if there are none.
+    pub fn load(conn: &mut PgConnection) -> Self {
+        let query = diesel::sql_query(
+            "SELECT lower(iso_code) AS code, \
+                    ST_XMin(ST_Extent(boundary)) AS min_lon, ST_YMin(ST_Extent(boundary)) AS min_lat, \
+                    ST_XMax(ST_Extent(boundary)) AS max_lon, ST_YMax(ST_Extent(boundary)) AS max_lat \
+             FROM countries WHERE iso_code IS NOT NULL \
+             GROUP BY lower(iso_code)",
+        );
+        log_query(&query);
+        let boxes: Vec<CountryBox> = query.load(conn).expect("can't query country boxes");
+        if boxes.is_empty() {
+            eprintln!("no countries with an ISO code, load them with UploadCountries first");
+            process::exit(1);
+        }
+        CountryBoxes(boxes.into_iter().map(|b| (b.code.clone(), b)).collect())
+    }
+
+    /// Whether the point is more than `margin` degrees outside the box of the country.
+    /// Countries without a box pass.
+    pub fn is_far_outside(&self, country: &str, lat: f64, lon: f64, margin: f64) -> bool {
+        let Some(b) = self.0.get(&country.trim().to_ascii_lowercase()) else {
+            return false;
+        };
+        lat < b.min_lat - margin
+            || lat > b.max_lat + margin
+            || lon < b.min_lon - margin
+            || lon > b.max_lon + margin
+    }
+}
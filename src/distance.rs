@@ -0,0 +1,262 @@
+//! Distances computed in the client, to skip the query for points which are known
+//! already and to check the results of PostGIS against an independent implementation.
+
+use clap::ValueEnum;
+
+use crate::LatLon;
+
+/// Semi-major axis of WGS 84 in meters.
+const WGS84_A: f64 = 6_378_137.0;
+/// Flattening of WGS 84.
+const WGS84_F: f64 = 1.0 / 298.257_223_563;
+/// Mean radius (2a + b) / 3 of WGS 84, the sphere of ST_DistanceSphere for SRID 4326.
+pub(crate) const MEAN_RADIUS: f64 = (2.0 * WGS84_A + WGS84_A * (1.0 - WGS84_F)) / 3.0;
+
+/// Iterations after which Vincenty's formula is given up, which only happens for
+/// nearly antipodal points.
+const VINCENTY_MAX_ITERATIONS: usize = 200;
+/// Starting azimuths of the Newton iterations for nearly antipodal points, spread over
+/// the half turn.
+const ANTIPODAL_STARTS: usize = 16;
+/// Iterations of each of them.
+const NEWTON_MAX_ITERATIONS: usize = 60;
+
+/// Where distances between points are computed.
+#[derive(Clone, Copy, ValueEnum)]
+pub(crate) enum DistanceImpl {
+    /// ST_DistanceSphere in the database
+    Db,
+    /// Haversine formula on the sphere of ST_DistanceSphere
+    Haversine,
+    /// Vincenty's inverse formula on the WGS 84 ellipsoid, comparable to
+    /// ST_DistanceSpheroid
+    Vincenty,
+}
+
+impl DistanceImpl {
+    /// Client side distance function in meters, `None` for the database.
+    pub(crate) fn client(self) -> Option<fn(LatLon, LatLon) -> f64> {
+        match self {
+            DistanceImpl::Db => None,
+            DistanceImpl::Haversine => Some(haversine),
+            DistanceImpl::Vincenty => Some(vincenty),
+        }
+    }
+}
+
+/// Great-circle distance in meters.
+pub(crate) fn haversine(a: LatLon, b: LatLon) -> f64 {
+    let (lat1, lat2) = (a.lat.to_radians(), b.lat.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.lon - a.lon).to_radians();
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * MEAN_RADIUS * h.sqrt().min(1.0).asin()
+}
+
+/// Geodesic distance in meters on WGS 84, accurate to well below a millimeter. Nearly
+/// antipodal points, where the iteration doesn't converge or converges to the line along
+/// the equator, which isn't the shortest one, are solved by `antipodal` instead.
+pub(crate) fn vincenty(a: LatLon, b: LatLon) -> f64 {
+    let b_axis = WGS84_A * (1.0 - WGS84_F);
+    let l = (b.lon - a.lon).to_radians();
+    let u1 = ((1.0 - WGS84_F) * a.lat.to_radians().tan()).atan();
+    let u2 = ((1.0 - WGS84_F) * b.lat.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+
+    let mut lambda = l;
+    for _ in 0..VINCENTY_MAX_ITERATIONS {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2)
+            + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2))
+        .sqrt();
+        if sin_sigma == 0.0 {
+            // the same point
+            return 0.0;
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_sq_alpha = 1.0 - sin_alpha * sin_alpha;
+        // both points on the equator
+        let cos_2sigma_m = if cos_sq_alpha == 0.0 {
+            0.0
+        } else {
+            cos_sigma - 2.0 * sin_u1 * sin_u2 / cos_sq_alpha
+        };
+        let c = WGS84_F / 16.0 * cos_sq_alpha * (4.0 + WGS84_F * (4.0 - 3.0 * cos_sq_alpha));
+        let previous = lambda;
+        lambda = l
+            + (1.0 - c)
+                * WGS84_F
+                * sin_alpha
+                * (sigma
+                    + c * sin_sigma
+                        * (cos_2sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))));
+        if lambda.abs() > std::f64::consts::PI {
+            break;
+        }
+        if (lambda - previous).abs() < 1e-12 {
+            let u_sq = cos_sq_alpha * (WGS84_A.powi(2) - b_axis.powi(2)) / b_axis.powi(2);
+            let (big_a, big_b) = vincenty_series(u_sq);
+            let delta_sigma = big_b
+                * sin_sigma
+                * (cos_2sigma_m
+                    + big_b / 4.0
+                        * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))
+                            - big_b / 6.0
+                                * cos_2sigma_m
+                                * (-3.0 + 4.0 * sin_sigma.powi(2))
+                                * (-3.0 + 4.0 * cos_2sigma_m.powi(2))));
+            return b_axis * big_a * (sigma - delta_sigma);
+        }
+    }
+    antipodal(a, b).unwrap_or_else(|| haversine(a, b))
+}
+
+/// Coefficients A and B of the series of Vincenty's formulas for `u_sq`.
+fn vincenty_series(u_sq: f64) -> (f64, f64) {
+    let big_a = 1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+    let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+    (big_a, big_b)
+}
+
+/// The point `s` meters away from `a` along the geodesic leaving it at `azimuth` radians
+/// from north, by Vincenty's direct formula, which converges everywhere. The longitude
+/// isn't normalized.
+fn vincenty_direct(a: LatLon, azimuth: f64, s: f64) -> LatLon {
+    let b_axis = WGS84_A * (1.0 - WGS84_F);
+    let u1 = ((1.0 - WGS84_F) * a.lat.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_az, cos_az) = azimuth.sin_cos();
+    let sigma1 = u1.tan().atan2(cos_az);
+    let sin_alpha = cos_u1 * sin_az;
+    let cos_sq_alpha = 1.0 - sin_alpha * sin_alpha;
+    let u_sq = cos_sq_alpha * (WGS84_A.powi(2) - b_axis.powi(2)) / b_axis.powi(2);
+    let (big_a, big_b) = vincenty_series(u_sq);
+
+    let mut sigma = s / (b_axis * big_a);
+    let mut cos_2sigma_m = (2.0 * sigma1 + sigma).cos();
+    for _ in 0..VINCENTY_MAX_ITERATIONS {
+        cos_2sigma_m = (2.0 * sigma1 + sigma).cos();
+        let (sin_sigma, cos_sigma) = sigma.sin_cos();
+        let delta_sigma = big_b
+            * sin_sigma
+            * (cos_2sigma_m
+                + big_b / 4.0
+                    * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))
+                        - big_b / 6.0
+                            * cos_2sigma_m
+                            * (-3.0 + 4.0 * sin_sigma.powi(2))
+                            * (-3.0 + 4.0 * cos_2sigma_m.powi(2))));
+        let previous = sigma;
+        sigma = s / (b_axis * big_a) + delta_sigma;
+        if (sigma - previous).abs() < 1e-14 {
+            break;
+        }
+    }
+    let (sin_sigma, cos_sigma) = sigma.sin_cos();
+    let x = sin_u1 * sin_sigma - cos_u1 * cos_sigma * cos_az;
+    let lat = (sin_u1 * cos_sigma + cos_u1 * sin_sigma * cos_az)
+        .atan2((1.0 - WGS84_F) * (sin_alpha * sin_alpha + x * x).sqrt());
+    let lambda = (sin_sigma * sin_az).atan2(cos_u1 * cos_sigma - sin_u1 * sin_sigma * cos_az);
+    let c = WGS84_F / 16.0 * cos_sq_alpha * (4.0 + WGS84_F * (4.0 - 3.0 * cos_sq_alpha));
+    let l = lambda
+        - (1.0 - c)
+            * WGS84_F
+            * sin_alpha
+            * (sigma
+                + c * sin_sigma
+                    * (cos_2sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m.powi(2))));
+    LatLon {
+        lat: lat.to_degrees(),
+        lon: a.lon + l.to_degrees(),
+    }
+}
+
+/// Geodesic distance between nearly antipodal points: the shortest of the geodesics
+/// from `a` hitting `b`, found by Newton's method on the azimuth and the length of
+/// `vincenty_direct` from several starting azimuths. `None` if none of them converges.
+fn antipodal(a: LatLon, b: LatLon) -> Option<f64> {
+    // differences of longitudes are taken the short way around
+    let miss = |azimuth: f64, s: f64| {
+        let p = vincenty_direct(a, azimuth, s);
+        (
+            p.lat - b.lat,
+            (p.lon - b.lon + 540.0).rem_euclid(360.0) - 180.0,
+        )
+    };
+    let (d_azimuth, d_s) = (1e-7, 1e-3);
+    let mut shortest: Option<f64> = None;
+    for start in 0..ANTIPODAL_STARTS {
+        let mut azimuth = start as f64 * std::f64::consts::TAU / ANTIPODAL_STARTS as f64;
+        let mut s = haversine(a, b);
+        for _ in 0..NEWTON_MAX_ITERATIONS {
+            let (dlat, dlon) = miss(azimuth, s);
+            // the Jacobian by finite differences
+            let (lat_az, lon_az) = miss(azimuth + d_azimuth, s);
+            let (lat_s, lon_s) = miss(azimuth, s + d_s);
+            let (j11, j12) = ((lat_az - dlat) / d_azimuth, (lat_s - dlat) / d_s);
+            let (j21, j22) = ((lon_az - dlon) / d_azimuth, (lon_s - dlon) / d_s);
+            let det = j11 * j22 - j12 * j21;
+            if det == 0.0 || !det.is_finite() {
+                break;
+            }
+            let step_azimuth = (j22 * dlat - j12 * dlon) / det;
+            let step_s = (j11 * dlon - j21 * dlat) / det;
+            azimuth -= step_azimuth;
+            s -= step_s;
+            if step_azimuth.abs() < 1e-15 && step_s.abs() < 1e-6 {
+                break;
+            }
+        }
+        // a tenth of a millimeter
+        let (dlat, dlon) = miss(azimuth, s);
+        if s > 0.0 && dlat.abs() < 1e-9 && dlon.abs() < 1e-9 && shortest.is_none_or(|d| s < d) {
+            shortest = Some(s);
+        }
+    }
+    shortest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(lat: f64, lon: f64) -> LatLon {
+        LatLon { lat, lon }
+    }
+
+    #[test]
+    fn vincenty_flinders_peak_to_buninyong() {
+        // the example of Vincenty's paper, 54 972.271 m
+        let flinders_peak = at(
+            -(37.0 + 57.0 / 60.0 + 3.72030 / 3600.0),
+            144.0 + 25.0 / 60.0 + 29.52440 / 3600.0,
+        );
+        let buninyong = at(
+            -(37.0 + 39.0 / 60.0 + 10.15610 / 3600.0),
+            143.0 + 55.0 / 60.0 + 35.38390 / 3600.0,
+        );
+        assert!((vincenty(flinders_peak, buninyong) - 54_972.271).abs() < 1e-3);
+    }
+
+    #[test]
+    fn vincenty_of_equatorial_antipodes_is_half_a_meridian() {
+        let d = vincenty(at(0.0, 0.0), at(0.0, 180.0));
+        assert!((d - 20_003_931.458_6).abs() < 1e-3, "{}", d);
+    }
+
+    #[test]
+    fn vincenty_of_nearly_antipodal_points() {
+        // Karney, Algorithms for geodesics (2013), 19 936 288.579 m
+        let d = vincenty(at(0.0, 0.0), at(0.5, 179.5));
+        assert!((d - 19_936_288.579).abs() < 1e-3, "{}", d);
+    }
+
+    #[test]
+    fn haversine_uses_the_mean_radius() {
+        let d = haversine(at(0.0, 0.0), at(0.0, 180.0));
+        assert!((d - std::f64::consts::PI * MEAN_RADIUS).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,133 @@
+//! Schema of the migrations as a single SQL script, to create the tables on a database
+//! this tool never connects to.
+
+use diesel::{migration::MigrationSource, pg::Pg};
+
+use crate::MIGRATIONS;
+
+/// Up migrations in the order they run. Every new migration has to be added here, `print`
+/// refuses to write an incomplete script.
+const UP_MIGRATIONS: [(&str, &str); 15] = [
+    (
+        "202210221357_cities",
+        include_str!("../migrations/202210221357_cities/up.sql"),
+    ),
+    (
+        "202610140900_population",
+        include_str!("../migrations/202610140900_population/up.sql"),
+    ),
+    (
+        "202610141000_load_runs",
+        include_str!("../migrations/202610141000_load_runs/up.sql"),
+    ),
+    (
+        "202610141100_raw",
+        include_str!("../migrations/202610141100_raw/up.sql"),
+    ),
+    (
+        "202610141200_nullable_region",
+        include_str!("../migrations/202610141200_nullable_region/up.sql"),
+    ),
+    (
+        "202610141300_geohash",
+        include_str!("../migrations/202610141300_geohash/up.sql"),
+    ),
+    (
+        "202610141400_display_name",
+        include_str!("../migrations/202610141400_display_name/up.sql"),
+    ),
+    (
+        "202610141500_city_points",
+        include_str!("../migrations/202610141500_city_points/up.sql"),
+    ),
+    (
+        "202610141600_countries",
+        include_str!("../migrations/202610141600_countries/up.sql"),
+    ),
+    (
+        "202610141700_bigint_id",
+        include_str!("../migrations/202610141700_bigint_id/up.sql"),
+    ),
+    (
+        "202610141800_region_centroids",
+        include_str!("../migrations/202610141800_region_centroids/up.sql"),
+    ),
+    (
+        "202610141900_loaded_at",
+        include_str!("../migrations/202610141900_loaded_at/up.sql"),
+    ),
+    (
+        "202610142000_source_srid",
+        include_str!("../migrations/202610142000_source_srid/up.sql"),
+    ),
+    (
+        "202610142100_latlon",
+        include_str!("../migrations/202610142100_latlon/up.sql"),
+    ),
+    (
+        "202610142200_rank",
+        include_str!("../migrations/202610142200_rank/up.sql"),
+    ),
+];
+
+/// Prints the up migrations in one transaction, followed by the rows diesel keeps of
+/// them, so running Upload against the new database doesn't apply them a second time.
+pub fn print() {
+    let embedded: Vec<String> = MigrationSource::<Pg>::migrations(&MIGRATIONS)
+        .expect("can't list migrations")
+        .iter()
+        .map(|m| m.name().to_string())
+        .collect();
+    let listed: Vec<&str> = UP_MIGRATIONS.iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        embedded, listed,
+        "migrations differ from the ones of DumpSchema"
+    );
+    println!("-- schema of the cities-loader migrations, apply with psql -f");
+    println!("BEGIN;");
+    for (name, sql) in UP_MIGRATIONS {
+        println!();
+        println!("-- {}", name);
+        println!("{}", sql.trim_end());
+    }
+    println!();
+    println!("-- migrations table of diesel");
+    println!("{}", diesel::migration::CREATE_MIGRATIONS_TABLE.trim_end());
+    let versions: Vec<String> = UP_MIGRATIONS
+        .iter()
+        .map(|(name, _)| format!("('{}')", name.split('_').next().unwrap()))
+        .collect();
+    println!(
+        "INSERT INTO __diesel_schema_migrations (version) VALUES {};",
+        versions.join(", ")
+    );
+    println!("COMMIT;");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+
+    #[test]
+    fn up_migrations_are_the_migrations_directory() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("migrations");
+        let mut migrations: Vec<(String, String)> = fs::read_dir(&dir)
+            .expect("can't list migrations")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_dir())
+            .map(|path| {
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let sql = fs::read_to_string(path.join("up.sql")).unwrap();
+                (name, sql)
+            })
+            .collect();
+        migrations.sort();
+        let listed: Vec<(String, String)> = UP_MIGRATIONS
+            .iter()
+            .map(|(name, sql)| (name.to_string(), sql.to_string()))
+            .collect();
+        assert_eq!(listed, migrations);
+    }
+}
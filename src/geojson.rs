@@ -0,0 +1,33 @@
+//! GeoJSON output of query results. Geometries are encoded by ST_AsGeoJSON, the loader
+//! only assembles the FeatureCollection around them.
+
+use serde_json::{json, Map, Value};
+
+use crate::City;
+
+/// Attributes of a city as feature properties.
+pub fn city_properties(city: &City) -> Map<String, Value> {
+    let mut properties = Map::new();
+    properties.insert("id".to_string(), json!(city.id));
+    properties.insert("country".to_string(), json!(city.country));
+    properties.insert("city".to_string(), json!(city.city));
+    properties.insert("accent_city".to_string(), json!(city.accent_city));
+    properties.insert("region".to_string(), json!(city.region));
+    properties.insert("population".to_string(), json!(city.population));
+    properties
+}
+
+/// FeatureCollection of cities with their ST_AsGeoJSON geometry, selected by the query
+/// which loaded them, and properties, in that order.
+pub fn feature_collection(features: Vec<(Option<&str>, Map<String, Value>)>) -> Value {
+    let features: Vec<Value> = features
+        .into_iter()
+        .map(|(geometry, properties)| {
+            let geometry = geometry.map_or(Value::Null, |g| {
+                serde_json::from_str(g).expect("ST_AsGeoJSON returned invalid JSON")
+            });
+            json!({"type": "Feature", "geometry": geometry, "properties": properties})
+        })
+        .collect();
+    json!({"type": "FeatureCollection", "features": features})
+}
@@ -0,0 +1,131 @@
+//! Consistency of the tables derived from or related to cities, which other commands
+//! build separately and which go stale or orphaned as cities are loaded and deleted.
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    sql_types::Text,
+    PgConnection, RunQueryDsl,
+};
+
+use crate::{log_query, Count, Exists};
+
+/// Prints a line per check, "ok", "skipped" with the reason, or the number of
+/// violations, and returns the process exit code: 1 if any check found violations.
+pub fn check(c_pool: &Pool<ConnectionManager<PgConnection>>) -> i32 {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let mut exists = |table: &str| {
+        diesel::sql_query("SELECT to_regclass($1) IS NOT NULL AS exists")
+            .bind::<Text, _>(table)
+            .get_result::<Exists>(&mut conn)
+            .unwrap_or_else(|e| panic!("can't look up table {}: {}", table, e))
+            .exists
+    };
+    if !exists("cities") {
+        println!("error: cities table doesn't exist, run Upload first");
+        return 1;
+    }
+    let has_city_points = exists("city_points");
+    let has_region_centroids = exists("region_centroids");
+    let has_countries = exists("countries");
+    let mut count = |sql: &str| {
+        let query = diesel::sql_query(sql);
+        log_query(&query);
+        query
+            .get_result::<Count>(&mut conn)
+            .unwrap_or_else(|e| panic!("can't check integrity: {}", e))
+            .count
+    };
+
+    let mut violations = 0;
+    let mut report = |name: &str, problems: Vec<Option<String>>| {
+        let found: Vec<String> = problems.into_iter().flatten().collect();
+        if found.is_empty() {
+            println!("{}: ok", name);
+        } else {
+            println!("{}: error: {}", name, found.join(", "));
+            violations += found.len();
+        }
+    };
+
+    if has_city_points {
+        // the constraint can be missing on a hand made schema or left NOT VALID, and
+        // session_replication_role = replica skips it altogether
+        let foreign_keys = count(
+            "SELECT count(*) AS count FROM pg_constraint \
+             WHERE contype = 'f' AND convalidated \
+             AND conrelid = 'city_points'::regclass AND confrelid = 'cities'::regclass",
+        );
+        let orphans = count(
+            "SELECT count(*) AS count FROM city_points p \
+             WHERE NOT EXISTS (SELECT 1 FROM cities c WHERE c.id = p.city_id)",
+        );
+        report(
+            "city_points",
+            vec![
+                (foreign_keys == 0).then(|| "no validated foreign key to cities".to_string()),
+                counted(orphans, "points of cities which don't exist"),
+            ],
+        );
+    } else {
+        println!("city_points: skipped, the table doesn't exist");
+    }
+
+    let centroids = if has_region_centroids {
+        count("SELECT count(*) AS count FROM region_centroids")
+    } else {
+        0
+    };
+    if centroids > 0 {
+        // a NULL region is a region of its own there, as in the GROUP BY which built it
+        let without_centroid = count(
+            "SELECT count(*) AS count FROM (SELECT DISTINCT country, region FROM cities) r \
+             WHERE NOT EXISTS (SELECT 1 FROM region_centroids rc \
+                               WHERE rc.country = r.country \
+                               AND rc.region IS NOT DISTINCT FROM r.region)",
+        );
+        let without_cities = count(
+            "SELECT count(*) AS count FROM region_centroids rc \
+             WHERE NOT EXISTS (SELECT 1 FROM cities c \
+                               WHERE c.country = rc.country \
+                               AND c.region IS NOT DISTINCT FROM rc.region)",
+        );
+        report(
+            "region_centroids",
+            vec![
+                counted(without_centroid, "regions of cities without centroid"),
+                counted(without_cities, "centroids of regions without cities"),
+            ],
+        );
+    } else {
+        println!("region_centroids: skipped, BuildRegionCentroids didn't run");
+    }
+
+    let boundaries = if has_countries {
+        count("SELECT count(*) AS count FROM countries")
+    } else {
+        0
+    };
+    if boundaries > 0 {
+        // matched like --check-country-bbox, the codes of the cities are lower case
+        let without_boundary = count(
+            "SELECT count(*) AS count FROM (SELECT DISTINCT country FROM cities) c \
+             WHERE NOT EXISTS (SELECT 1 FROM countries b \
+                               WHERE lower(b.iso_code) = lower(c.country))",
+        );
+        report(
+            "countries",
+            vec![counted(
+                without_boundary,
+                "country codes of cities without boundary",
+            )],
+        );
+    } else {
+        println!("countries: skipped, UploadCountries didn't run");
+    }
+
+    (violations > 0) as i32
+}
+
+fn counted(violations: i64, what: &str) -> Option<String> {
+    (violations > 0).then(|| format!("{} {}", violations, what))
+}
@@ -0,0 +1,180 @@
+use std::{fs::File, io, path::Path};
+
+use chrono::{DateTime, Utc};
+use diesel::{
+    dsl::now,
+    r2d2::{ConnectionManager, Pool},
+    table, ExpressionMethods, Insertable, OptionalExtension, PgConnection, QueryDsl, Queryable,
+    RunQueryDsl,
+};
+use sha2::{Digest, Sha256};
+
+pub const RUNNING: &str = "running";
+pub const SUCCEEDED: &str = "succeeded";
+pub const FAILED: &str = "failed";
+
+/// Streams the file through SHA-256 and returns the hex encoded digest.
+pub fn file_sha256(path: &Path) -> String {
+    let mut f = File::open(path)
+        .unwrap_or_else(|e| panic!("can't open cities file {}: {}", path.display(), e));
+    let mut hasher = Sha256::new();
+    io::copy(&mut f, &mut hasher).expect("can't read cities file");
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Queryable)]
+pub struct LoadRun {
+    pub id: i32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub rows_inserted: i64,
+    pub input_file: String,
+    pub input_sha256: String,
+    pub status: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name=load_runs)]
+struct NewLoadRun<'a> {
+    input_file: &'a str,
+    input_sha256: &'a str,
+    status: &'a str,
+}
+
+/// Returns the latest successful run which loaded a file with the same hash.
+pub fn find_succeeded(conn: &mut PgConnection, sha256: &str) -> Option<LoadRun> {
+    load_runs::table
+        .filter(load_runs::input_sha256.eq(sha256))
+        .filter(load_runs::status.eq(SUCCEEDED))
+        .order_by(load_runs::id.desc())
+        .first(conn)
+        .optional()
+        .expect("can't query load runs")
+}
+
+/// Row in load_runs for the current Upload. If it's dropped without `finish`,
+/// e.g. while unwinding a panic, the run is marked as failed.
+pub struct LoadRunGuard {
+    /// `None` for a load without database, which has no row to update.
+    pool: Option<Pool<ConnectionManager<PgConnection>>>,
+    id: i32,
+    finished: bool,
+}
+
+impl LoadRunGuard {
+    pub fn start(
+        pool: &Pool<ConnectionManager<PgConnection>>,
+        input_file: &str,
+        input_sha256: &str,
+    ) -> Self {
+        let mut conn = pool.get().expect("can't get connection");
+        let id = diesel::insert_into(load_runs::table)
+            .values(NewLoadRun {
+                input_file,
+                input_sha256,
+                status: RUNNING,
+            })
+            .returning(load_runs::id)
+            .get_result(&mut conn)
+            .expect("can't record load run");
+        LoadRunGuard {
+            pool: Some(pool.clone()),
+            id,
+            finished: false,
+        }
+    }
+
+    /// Guard of a load which isn't recorded, e.g. one writing SQL to a file.
+    pub fn detached() -> Self {
+        LoadRunGuard {
+            pool: None,
+            id: 0,
+            finished: false,
+        }
+    }
+
+    pub fn finish(mut self, rows_inserted: usize, status: &str) {
+        if let Some(pool) = &self.pool {
+            let mut conn = pool.get().expect("can't get connection");
+            update_run(&mut conn, self.id, rows_inserted as i64, status)
+                .expect("can't record load run");
+        }
+        self.finished = true;
+    }
+}
+
+impl Drop for LoadRunGuard {
+    fn drop(&mut self) {
+        let pool = match &self.pool {
+            Some(pool) if !self.finished => pool,
+            _ => return,
+        };
+        // must not panic here, the guard is usually dropped during unwinding
+        if let Ok(mut conn) = pool.get() {
+            let _ = diesel::update(load_runs::table.find(self.id))
+                .set((load_runs::finished_at.eq(now), load_runs::status.eq(FAILED)))
+                .execute(&mut conn);
+        }
+    }
+}
+
+fn update_run(
+    conn: &mut PgConnection,
+    id: i32,
+    rows_inserted: i64,
+    status: &str,
+) -> diesel::QueryResult<usize> {
+    diesel::update(load_runs::table.find(id))
+        .set((
+            load_runs::finished_at.eq(now),
+            load_runs::rows_inserted.eq(rows_inserted),
+            load_runs::status.eq(status),
+        ))
+        .execute(conn)
+}
+
+pub fn print_history(c_pool: &Pool<ConnectionManager<PgConnection>>, limit: i64) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let runs: Vec<LoadRun> = load_runs::table
+        .order_by(load_runs::id.desc())
+        .limit(limit)
+        .load(&mut conn)
+        .expect("can't query load runs");
+    for run in runs {
+        let finished = match run.finished_at {
+            Some(finished_at) => format!(
+                "{} ({}s)",
+                finished_at.format("%Y-%m-%d %H:%M:%S"),
+                (finished_at - run.started_at).num_seconds()
+            ),
+            None => "-".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}\t{}\t{} rows\t{}\t{}",
+            run.id,
+            run.status,
+            run.started_at.format("%Y-%m-%d %H:%M:%S"),
+            finished,
+            run.rows_inserted,
+            run.input_file,
+            // empty for an input read from a pipe
+            run.input_sha256.get(..12).unwrap_or("-"),
+        );
+    }
+}
+
+table! {
+    load_runs (id) {
+        id -> Int4,
+        started_at -> Timestamptz,
+        finished_at -> Nullable<Timestamptz>,
+        rows_inserted -> Int8,
+        input_file -> Text,
+        input_sha256 -> Text,
+        status -> Text,
+    }
+}
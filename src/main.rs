@@ -1,132 +1,5044 @@
 use std::{
-    env,
-    fs::File,
-    io::BufReader,
-    time::{Duration, SystemTime}, path::Path,
+    collections::{HashMap, HashSet},
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+    iter::Peekable,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use clap::{Parser, Subcommand};
-use csv::StringRecord;
+use bzip2::read::MultiBzDecoder;
+use chrono::{DateTime, Utc};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use csv::{QuoteStyle, StringRecord};
 use diesel::{
-    r2d2::{Builder, ConnectionManager, Pool, PooledConnection},
-    PgConnection, QueryDsl, RunQueryDsl,
+    connection::DefaultLoadingMode,
+    expression::{SqlLiteral, UncheckedBind},
+    helper_types::AsExprOf,
+    pg::Pg,
+    query_builder::{bind_collector::RawBytesBindCollector, BindCollector, QueryFragment},
+    r2d2::{Builder, ConnectionManager, CustomizeConnection, Pool, PooledConnection},
+    BoxableExpression, Column, Connection, ExpressionMethods, OptionalExtension, PgConnection,
+    PgTextExpressionMethods, QueryDsl, RunQueryDsl,
+};
+use diesel::{
+    sql_function,
+    sql_types::{Array, Bool, Float8, Int4, Int8, Json, Jsonb, Nullable, Text},
+    table, BoolExpressionMethods, Insertable, Queryable, QueryableByName, Selectable,
+    SelectableHelper,
 };
-use diesel::{table, Insertable};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use dotenv::dotenv;
-use postgis_diesel::types::Point;
-use rand::{thread_rng, Rng};
-use serde::Deserialize;
+use flate2::read::MultiGzDecoder;
+use postgis_diesel::{sql_types::Geometry, types::Point};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use xz2::read::XzDecoder;
+
+mod analyze;
+mod columns;
+mod countries;
+mod distance;
+mod dump_schema;
+mod geojson;
+mod integrity;
+mod load_runs;
+mod nearest_batch;
+mod profile;
+mod repl;
+mod report;
+mod rows;
+#[cfg(feature = "serve")]
+mod serve;
+mod shutdown;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod zip_stream;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+
+fn run_migration(conn: &mut PooledConnection<ConnectionManager<PgConnection>>) {
+    conn.run_pending_migrations(MIGRATIONS)
+        .expect("migration failure");
+}
+
+fn database_url(dotenv_path: Option<&Path>, url_file: Option<&Path>) -> String {
+    if let Some(path) = url_file {
+        let url = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("can't read connection URL from {}: {}", path.display(), e));
+        // secret files usually end with a newline
+        let url = url.trim_end_matches(['\r', '\n']);
+        if url.is_empty() {
+            panic!("connection URL file {} is empty", path.display());
+        }
+        return url.to_string();
+    }
+    match dotenv_path {
+        Some(path) => {
+            dotenv::from_path(path)
+                .unwrap_or_else(|e| panic!("can't load {}: {}", path.display(), e));
+        }
+        None => {
+            dotenv().ok();
+        }
+    }
+    env::var("DATABASE_URL").expect("DATABASE_URL not set")
+}
+
+/// Checks the shape of the connection URL, so a typo fails right away instead of as
+/// a connection error. libpq keyword/value strings like "host=... dbname=..." pass as
+/// they are. Messages don't repeat the URL, it may contain a password.
+fn validate_database_url(url: &str) -> Result<(), String> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        if url.contains('=') {
+            return Ok(());
+        }
+        return Err("expected postgres://host/database or libpq key=value pairs".to_string());
+    };
+    if scheme != "postgres" && scheme != "postgresql" {
+        return Err(format!(
+            "scheme is \"{}\", expected postgres:// or postgresql://",
+            scheme
+        ));
+    }
+    let (authority, params) = match rest.split_once(['/', '?']) {
+        Some((authority, _)) => (authority, rest.split_once('?').map_or("", |(_, q)| q)),
+        None => (rest, ""),
+    };
+    // user info may contain '@' only percent-encoded, so the host follows the last one
+    let host = authority.rsplit('@').next().unwrap_or("");
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+    // postgres:///db connects to the default socket, a user or port without a host is
+    // likely a typo unless a host= parameter gives the socket
+    if host.is_empty()
+        && !authority.is_empty()
+        && !params.split('&').any(|p| p.starts_with("host="))
+    {
+        return Err("URL has no host".to_string());
+    }
+    Ok(())
+}
+
+/// Sets application_name on every new connection, so they can be told apart in pg_stat_activity.
+#[derive(Debug)]
+struct ApplicationName(String);
+
+impl ApplicationName {
+    fn apply(&self, conn: &mut PgConnection) -> diesel::QueryResult<()> {
+        // SET doesn't accept bind parameters, set_config does
+        diesel::sql_query("SELECT set_config('application_name', $1, false)")
+            .bind::<Text, _>(&self.0)
+            .execute(conn)
+            .map(|_| ())
+    }
+}
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for ApplicationName {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        self.apply(conn).map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+fn init_connection_pool(database_url: String, cli: &Cli) -> Pool<ConnectionManager<PgConnection>> {
+    let cm = ConnectionManager::new(database_url);
+    Builder::new()
+        .connection_customizer(Box::new(ApplicationName(cli.app_name.clone())))
+        .max_size(20)
+        .min_idle(Some(1))
+        .max_lifetime(seconds_or_none(cli.max_lifetime))
+        .idle_timeout(seconds_or_none(cli.idle_timeout))
+        .build(cm)
+        .expect("can't create connection pool!")
+}
+
+/// 0 disables a pool timeout.
+fn seconds_or_none(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Environment variables backing flags, listed in the help since most of them belong to
+/// subcommands.
+const ENV_HELP: &str = "\
+Environment:
+  DATABASE_URL                Postgres connection URL, also read from .env
+  CITIES_DOTENV_PATH          --dotenv-path
+  CITIES_CONNECTION_URL_FILE  --connection-url-file
+  CITIES_GEOMETRY_COLUMN      --geometry-column
+  CITIES_APP_NAME             --app-name
+  CITIES_MAX_LIFETIME         --max-lifetime
+  CITIES_IDLE_TIMEOUT         --idle-timeout
+  CITIES_BACKEND              --backend
+  CITIES_SQLITE_PATH          --sqlite-path
+  CITIES_INPUT                --input of Upload, Diff and Tune
+  CITIES_READ_BUFFER_BYTES    --read-buffer-bytes of Upload, Diff and Tune
+  CITIES_BATCH_SIZE           --batch-size of Upload
+  CITIES_INSERT_MODE          --insert-mode of Upload
+  CITIES_SRID                 --srid of Upload
+  CITIES_METRICS_FILE         --metrics-file of Upload
+Flags override the environment. Only DATABASE_URL is taken from .env, the file is read
+after the flags are parsed.";
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None, after_help = ENV_HELP)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+    /// Load environment from this file instead of ./.env
+    #[arg(long, global = true, env = "CITIES_DOTENV_PATH")]
+    dotenv_path: Option<PathBuf>,
+    /// Read the connection URL from this file, e.g. a mounted secret, instead of
+    /// DATABASE_URL, so it doesn't show up in the process list or the environment
+    #[arg(long, global = true, env = "CITIES_CONNECTION_URL_FILE")]
+    connection_url_file: Option<PathBuf>,
+    /// Name of the geometry column of the cities table. Only the raw SQL paths (Upload
+    /// with --insert-mode unnest or --sql-out, MajorCities, PruneBbox, ClosestPair,
+    /// NearestBatch, Extent, Backfill, BuildLod, BuildRegionCentroids, Analyze) support it,
+    /// query builder based commands are bound to the `location` column of the static diesel
+    /// schema
+    #[arg(long, global = true, default_value = DEFAULT_GEOMETRY_COLUMN, value_parser = parse_identifier, env = "CITIES_GEOMETRY_COLUMN")]
+    geometry_column: String,
+    /// application_name of the database connections
+    #[arg(
+        long,
+        global = true,
+        default_value = "cities-loader",
+        env = "CITIES_APP_NAME"
+    )]
+    app_name: String,
+    /// Seconds after which pooled connections are closed and replaced, 0 keeps them open
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 1800,
+        env = "CITIES_MAX_LIFETIME"
+    )]
+    max_lifetime: u64,
+    /// Seconds after which idle connections above the first are closed, 0 keeps them open
+    #[arg(
+        long,
+        global = true,
+        default_value_t = 600,
+        env = "CITIES_IDLE_TIMEOUT"
+    )]
+    idle_timeout: u64,
+    /// Print generated SQL of queries to stderr (batch inserts are not printed)
+    #[arg(long, global = true)]
+    verbose: bool,
+    /// Before querying cities (Nearest, NearestBatch, ClosestPair, Bench, Repl, Serve), check
+    /// that all their geometries have SRID 4326 and fail otherwise. Reads every row once
+    #[arg(long, global = true)]
+    strict_srid: bool,
+    /// Database to use; sqlite needs a build with the sqlite feature and SpatiaLite
+    /// installed, and supports only Upload and Nearest
+    #[arg(long, global = true, value_enum, default_value_t = Backend::Postgres, env = "CITIES_BACKEND")]
+    backend: Backend,
+    /// SQLite database file of --backend sqlite, in memory by default
+    #[arg(
+        long,
+        global = true,
+        default_value = ":memory:",
+        env = "CITIES_SQLITE_PATH"
+    )]
+    sqlite_path: PathBuf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+    Postgres,
+    Sqlite,
+}
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Prints the SQL and binds of the query to stderr when --verbose is set.
+fn log_query<T: QueryFragment<Pg>>(query: &T) {
+    if VERBOSE.load(Ordering::Relaxed) {
+        eprintln!("{}", diesel::debug_query::<Pg, _>(query));
+    }
+}
+
+const DEFAULT_GEOMETRY_COLUMN: &str = "location";
+
+fn parse_identifier(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if valid_start && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(s.to_string())
+    } else {
+        Err(format!("\"{}\" is not a valid column name", s))
+    }
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Upload data to postgres DB
+    Upload(Box<UploadArgs>),
+    /// Execute sequentially 500 requests to find 500 nearest neighbors in a loop
+    Bench(BenchArgs),
+    /// Write a CSV of random cities in the Upload input format, to try the loader
+    /// without downloading the real data
+    Generate {
+        /// Number of cities
+        #[arg(long, default_value_t = 10_000)]
+        count: usize,
+        /// CSV file to write
+        #[arg(long)]
+        output: PathBuf,
+        /// Seed of the random cities, the same seed generates the same file
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    /// Print the schema the migrations create as one SQL script, to set up the tables
+    /// on another database without connecting to it
+    DumpSchema,
+    /// Load a sample of the input with a sweep of batch sizes into a temporary copy of
+    /// the cities table and compare the throughput, to pick --batch-size of Upload
+    Tune(TuneArgs),
+    /// Create the major_cities materialized view, replacing an existing one, or refresh it
+    MajorCities {
+        /// Minimal population of a city to be included into the view
+        #[arg(long, default_value_t = 100_000)]
+        min_population: i64,
+        /// Refresh the existing view instead of creating it, with the population threshold
+        /// it was created with
+        #[arg(long, conflicts_with = "min_population")]
+        refresh: bool,
+    },
+    /// Find cities nearest to a point
+    Nearest(NearestArgs),
+    /// Write all cities to stdout or a file, as CSV in the Upload input format or as NDJSON
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        /// Field delimiter of the CSV output
+        #[arg(long, default_value = ",", value_parser = parse_csv_byte)]
+        delimiter: u8,
+        /// Tab separated CSV output without quoting, same as the GeoNames dumps
+        #[arg(long, conflicts_with = "delimiter")]
+        tsv: bool,
+        /// Only cities loaded after this RFC 3339 time, e.g. 2026-10-14T09:00:00Z, for
+        /// incremental exports
+        #[arg(long)]
+        since: Option<DateTime<Utc>>,
+        /// Sort by accented name in the alphabetical order of this language, e.g. fr or
+        /// de-CH, instead of by id. Uses the ICU collation of Postgres, which needs a
+        /// server built with ICU and a UTF8 database
+        #[arg(long, value_parser = parse_locale)]
+        locale: Option<String>,
+        /// Cities read per query when ordered by id, each query continues after the last
+        /// id of the previous one, so memory stays bounded on large tables
+        #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(i64).range(1..))]
+        chunk_size: i64,
+        /// Continue an interrupted export after this id, the last one it reported. Appends
+        /// to --output-file without a second CSV header
+        #[arg(long, conflicts_with = "locale")]
+        resume_from_id: Option<i64>,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+    },
+    /// Delete cities outside (or inside with --invert) of a bounding box
+    PruneBbox {
+        #[arg(long, allow_negative_numbers = true)]
+        min_lat: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        min_lon: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        max_lat: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        max_lon: f64,
+        /// Delete cities inside of the box instead
+        #[arg(long)]
+        invert: bool,
+        /// Only print how many cities would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Read queries from stdin in a loop over a single connection
+    Repl {
+        /// Units of the printed distances
+        #[arg(long, value_enum, default_value_t = Units::Km)]
+        units: Units,
+        /// Keep the results of this many recent `near` lookups in memory, keyed by the
+        /// point rounded to about a meter and the limit. 0 disables the cache
+        #[arg(long, default_value_t = 0)]
+        cache_size: usize,
+        /// Print the distances of `near` as computed by the database, or recompute them
+        /// here from the query point, which also makes cached results exact for every
+        /// point of their key
+        #[arg(long, value_enum, default_value_t = distance::DistanceImpl::Db)]
+        distance_impl: distance::DistanceImpl,
+    },
+    /// Rebuild the spatial index of the geometry column, or all indexes of the cities table
+    /// with --table
+    Reindex {
+        /// Reindex the whole cities table
+        #[arg(long)]
+        table: bool,
+        /// Rebuild without locking out writes (REINDEX ... CONCURRENTLY)
+        #[arg(long)]
+        concurrently: bool,
+    },
+    /// Print the disk usage of the cities table, its heap and its indexes
+    DiskUsage,
+    /// Print the bounding box and its center of all cities, e.g. for the initial
+    /// viewport of a map
+    Extent {
+        /// Only cities of this country (case insensitive)
+        #[arg(long)]
+        country: Option<String>,
+        /// Count the cities within this many degrees of longitude of the antimeridian,
+        /// where bounding boxes wrap around
+        #[arg(long, default_value_t = 1.0, value_parser = parse_margin_degrees)]
+        antimeridian_margin: f64,
+    },
+    /// Check that the database is reachable and the schema exists
+    Ping,
+    /// Check that the spatial index of the geometry column, cities_<column>_idx, exists and
+    /// the planner uses it for nearest neighbor queries, exits with 1 otherwise
+    IndexCheck {
+        /// Plan the query with sequential scans disabled. Small tables, e.g. test data in
+        /// CI, are read faster without the index, so only this shows the index is usable
+        #[arg(long)]
+        disable_seqscan: bool,
+        /// Print the plan as the JSON of EXPLAIN (FORMAT JSON) instead of the status line,
+        /// for CI to assert on its nodes; the exit code is the same
+        #[arg(long, value_enum, default_value_t = ExplainFormat::Text)]
+        explain_format: ExplainFormat,
+    },
+    /// Check that city_points, region_centroids and countries are consistent with the
+    /// cities: the foreign key holds, no point or centroid is orphaned and every region
+    /// and country has its row. Exits with 1 on any violation
+    IntegrityCheck,
+    /// Print recent Upload runs
+    LoadHistory {
+        /// Number of runs to print
+        #[arg(long, default_value_t = 10)]
+        limit: i64,
+    },
+    /// Print pairwise sphere distances in meters between points as a CSV matrix
+    DistanceMatrix {
+        /// Point as "lat,lon", can be repeated
+        #[arg(long = "point")]
+        points: Vec<LatLon>,
+        /// File with one "lat,lon" point per line, appended after --point values
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// Compute the distances in the database or here, without connecting to it
+        #[arg(long, value_enum, default_value_t = distance::DistanceImpl::Db)]
+        distance_impl: distance::DistanceImpl,
+    },
+    /// Compute a derived column for cities which don't have it yet
+    Backfill {
+        #[arg(value_enum)]
+        column: BackfillColumn,
+        /// Number of geohash characters
+        #[arg(long, default_value_t = DEFAULT_GEOHASH_PRECISION, value_parser = clap::value_parser!(i32).range(1..=20))]
+        geohash_precision: i32,
+        /// SQL expression over the columns of a city computing the rank, see
+        /// Upload --rank-expression
+        #[arg(long, default_value = DEFAULT_RANK_EXPRESSION)]
+        rank_expression: String,
+        /// Recompute the column for all cities, e.g. after changing the precision
+        #[arg(long)]
+        all: bool,
+        /// Cities updated per statement, each batch commits on its own so locks are held
+        /// briefly and an interrupted backfill keeps its progress
+        #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(i64).range(1..))]
+        batch_size: i64,
+        /// Continue an interrupted backfill after this id, the last one it reported, e.g.
+        /// to skip the batches already done by a backfill with --all
+        #[arg(long)]
+        resume_from_id: Option<i64>,
+    },
+    /// Compare an input file with the cities table, keyed by country, city and region,
+    /// and print added, removed and moved cities as CSV
+    Diff(DiffArgs),
+    /// Replace the countries table with the boundaries of a GeoJSON FeatureCollection
+    UploadCountries {
+        /// GeoJSON file, .zip, .gz, .bz2 and .xz files are decompressed
+        #[arg(long)]
+        input: PathBuf,
+        /// Feature property with the country name
+        #[arg(long, default_value = "name")]
+        name_property: String,
+        /// Feature property with the country code, features without it get a NULL code
+        #[arg(long, default_value = "iso_a2")]
+        code_property: String,
+    },
+    /// Print the country containing a point, from the boundaries of UploadCountries;
+    /// exits with 1 if there is none
+    CountryOf {
+        #[arg(long, allow_negative_numbers = true)]
+        lat: f64,
+        #[arg(long, allow_negative_numbers = true)]
+        lon: f64,
+    },
+    /// Create (or recreate) a cities_lod_<zoom> table per zoom level with the most
+    /// populous city of every grid cell, for map rendering without overplotting
+    BuildLod {
+        /// Web map zoom levels, e.g. 2,5,8
+        #[arg(long, required = true, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..=22))]
+        levels: Vec<u8>,
+    },
+    /// Recompute the region_centroids table, the centroid of the cities of every country
+    /// and region. That's the middle of the point cloud, pulled towards where cities are
+    /// dense, not the geographic or administrative center of the region
+    BuildRegionCentroids,
+    /// Find the closest pairs of cities, often duplicates at slightly different coordinates
+    ClosestPair {
+        /// Number of pairs to print, closest first
+        #[arg(long, default_value_t = 1)]
+        top: i64,
+        /// Only look at cities of this country (case insensitive)
+        #[arg(long)]
+        country: Option<String>,
+        /// Units of the printed distances
+        #[arg(long, value_enum, default_value_t = Units::Km)]
+        units: Units,
+    },
+    /// Answer GET /nearest?lat=..&lon=..&limit=.. over HTTP with JSON, needs a build with
+    /// the serve feature
+    Serve {
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// Address to listen on, 0.0.0.0 for all interfaces
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: String,
+    },
+    /// Write the nearest city of every point of a CSV file with a header as CSV
+    NearestBatch {
+        /// CSV of points, may be compressed like the Upload input
+        input_points_csv: PathBuf,
+        /// Column with the latitude of the points
+        #[arg(long, default_value = "latitude")]
+        lat_column: String,
+        /// Column with the longitude of the points
+        #[arg(long, default_value = "longitude")]
+        lon_column: String,
+        /// Points looked up with a single query
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u32).range(1..))]
+        batch_size: u32,
+        /// Write to this file instead of stdout
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+    },
+    /// Report data quality measures of the loaded cities
+    Analyze {
+        #[command(subcommand)]
+        check: analyze::AnalyzeCheck,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BackfillColumn {
+    /// ST_GeoHash of the location, for prefix based grouping without spatial operators
+    Geohash,
+    /// The accented name, or the plain one where the accented name is empty
+    DisplayName,
+    /// Sort key of important cities, see Upload --rank-expression
+    Rank,
+}
+
+impl BackfillColumn {
+    fn name(self) -> &'static str {
+        match self {
+            BackfillColumn::Geohash => "geohash",
+            BackfillColumn::DisplayName => "display_name",
+            BackfillColumn::Rank => "rank",
+        }
+    }
+
+    /// SQL computing the column from the other columns of a city.
+    fn expression(
+        self,
+        geohash_precision: i32,
+        rank_expression: &str,
+        geometry_column: &str,
+    ) -> String {
+        match self {
+            // the precision is range checked by clap, so it's safe to inline
+            BackfillColumn::Geohash => {
+                format!("ST_GeoHash(\"{}\", {})", geometry_column, geohash_precision)
+            }
+            BackfillColumn::DisplayName => "COALESCE(NULLIF(accent_city, ''), city)".to_string(),
+            BackfillColumn::Rank => rank_expression.to_string(),
+        }
+    }
+}
+
+const DEFAULT_GEOHASH_PRECISION: i32 = 9;
+
+/// Natural logarithm of the population, so ten times the population adds about 2.3 to
+/// the rank and a few megacities don't dwarf everything else. Cities without population
+/// rank 0, like a village.
+const DEFAULT_RANK_EXPRESSION: &str = "ln(1 + COALESCE(population, 0))";
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NullCoordinatePolicy {
+    /// Count the row as skipped and drop it
+    Skip,
+    /// Abort the load
+    Error,
+    /// Insert the city with a NULL location
+    NullGeometry,
+}
+
+#[derive(Args)]
+struct UploadArgs {
+    #[command(flatten)]
+    csv: CsvArgs,
+    /// Number of rows inserted per batch
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..), env = "CITIES_BATCH_SIZE")]
+    batch_size: u32,
+    /// How rows are sent to the database
+    #[arg(long, value_enum, default_value_t = InsertMode::Values, env = "CITIES_INSERT_MODE")]
+    insert_mode: InsertMode,
+    /// When the index of the geometry column, cities_<column>_idx, is built. It trades the speed of the load against nearest
+    /// queries during it, which read the whole table while the index is missing; a load
+    /// which fails midway leaves it missing until the next successful one
+    #[arg(long, value_enum, default_value_t = IndexStrategy::During)]
+    index_strategy: IndexStrategy,
+    /// Batches inserted between the index builds of --index-strategy periodic
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    index_every: u32,
+    /// Finish successfully even if the input has no data rows
+    #[arg(long)]
+    allow_empty: bool,
+    /// Skip the load if a file with the same SHA-256 was already loaded successfully
+    #[arg(long)]
+    skip_unchanged: bool,
+    /// Abort before loading anything unless the SHA-256 of the input file is this hex
+    /// digest, e.g. the published checksum of the download
+    #[arg(long, value_parser = parse_sha256)]
+    expected_sha256: Option<String>,
+    /// Store every source row as a JSON object in the raw column, roughly doubles the table size
+    #[arg(long)]
+    keep_raw: bool,
+    /// Skip rows which can't be parsed instead of aborting, every skipped row is logged
+    #[arg(long)]
+    continue_on_error: bool,
+    /// Write Prometheus metrics of the finished load to this file, e.g. for the
+    /// node_exporter textfile collector
+    #[arg(long, env = "CITIES_METRICS_FILE")]
+    metrics_file: Option<PathBuf>,
+    /// Print how the load time splits into parsing, building cities and inserting them.
+    /// In values mode the geometries of every batch are additionally encoded on their own
+    /// to show their share of the insert
+    #[arg(long)]
+    timings: bool,
+    /// Write a Chrome trace of the load stages and every batch to this file when the load
+    /// finishes, for chrome://tracing, Perfetto or speedscope. Parsing on --jobs worker
+    /// threads shows up as part of reading the rows
+    #[arg(long)]
+    profile: Option<PathBuf>,
+    /// Compute the geohash column with this many characters for the new cities after
+    /// the load
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..=20))]
+    geohash_precision: Option<i32>,
+    /// Compute the rank column of the new cities after the load, a precomputed sort key
+    /// for ORDER BY rank DESC queries of important cities
+    #[arg(long)]
+    compute_rank: bool,
+    /// SQL expression over the columns of a city computing its rank, higher is more
+    /// important. It's run as is, e.g. 'ln(1 + COALESCE(population, 0)) + CASE WHEN region
+    /// IS NULL THEN 0 ELSE 1 END'
+    #[arg(long, default_value = DEFAULT_RANK_EXPRESSION)]
+    rank_expression: String,
+    /// Fill the display_name column with the accented name, or the plain one where the
+    /// accented name is empty
+    #[arg(long)]
+    with_display_name: bool,
+    /// Also store the input coordinates in the plain latitude and longitude columns, for
+    /// clients without PostGIS
+    #[arg(long)]
+    with_latlon: bool,
+    /// What to do with rows without latitude or longitude. null-geometry is refused unless
+    /// the geometry column of the cities table was made nullable, and not available with
+    /// --backend sqlite
+    #[arg(long, value_enum, default_value_t = NullCoordinatePolicy::Error)]
+    null_coordinate_policy: NullCoordinatePolicy,
+    /// SRID of the input coordinates, checked against spatial_ref_sys before loading. They
+    /// are transformed to the 4326 of the geometry column, the srid column keeps this one
+    #[arg(long, default_value_t = 4326, env = "CITIES_SRID")]
+    srid: u32,
+    /// Resume a failed load at the batch of its last "inserting N batch" line, skipping
+    /// the cities of all earlier batches. Only correct with the same input, batch size and
+    /// row options as the failed run, since batches are counted, not matched
+    #[arg(long, default_value_t = 0)]
+    continue_from_batch: usize,
+    /// Progress file recording how many cities of the input are stored, updated after
+    /// every batch. If it exists at the start and belongs to the same input, the load
+    /// continues after those cities; it's removed when the load succeeds
+    #[arg(long, conflicts_with = "continue_from_batch")]
+    checkpoint_file: Option<PathBuf>,
+    /// Also insert the cities read so far and update --checkpoint-file every this many
+    /// seconds, so a crash loses at most that much work however slow the input is read
+    #[arg(long, requires = "checkpoint_file", value_parser = clap::value_parser!(u64).range(1..))]
+    checkpoint_every_secs: Option<u64>,
+    /// Insert with synchronous_commit off, so commits don't wait for the WAL flush. A
+    /// server crash can lose the last committed batches, though it doesn't corrupt the
+    /// database; only worth it with small batches, which commit often
+    #[arg(long)]
+    async_commit: bool,
+    /// Threads deserializing rows, the input is still read and split into rows by one
+    /// thread and inserted in order. 1 parses on the main thread
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+    /// Abort when more than this fraction of the rows read so far was skipped, checked
+    /// every --batch-size rows and at the end. A file skipping most rows under
+    /// --continue-on-error is likely broken or mapped wrongly rather than a bit dirty
+    #[arg(long, value_parser = parse_ratio)]
+    max_skip_ratio: Option<f64>,
+    /// Abort once more than this many rows couldn't be parsed under --continue-on-error,
+    /// printing the first errors
+    #[arg(long, requires = "continue_on_error")]
+    max_errors: Option<usize>,
+    /// Write the inserts as SQL script to this file instead of connecting to the
+    /// database, e.g. for a DBA to apply with psql --single-transaction. The schema must
+    /// exist there already; the SRID isn't checked and the load isn't recorded
+    #[arg(long, conflicts_with_all = ["skip_unchanged", "geohash_precision", "compute_rank", "check_country_bbox", "extra_points", "async_commit", "conflict_on"])]
+    sql_out: Option<PathBuf>,
+    /// Update the existing city instead of inserting a new one when these columns match,
+    /// e.g. country,city,region. A unique constraint or index on exactly these columns
+    /// must exist; NULLs never match unless it's NULLS NOT DISTINCT. Batches are sent as
+    /// with --insert-mode unnest and mustn't repeat a key, see --dedupe-input
+    #[arg(long, value_delimiter = ',', value_parser = parse_identifier)]
+    conflict_on: Vec<String>,
+    /// Remove the files extracted from a .zip input after a successful load, the archive
+    /// itself is kept. --stream-zip extracts nothing
+    #[arg(long, conflicts_with = "stream_zip")]
+    cleanup_extracted: bool,
+    /// Write counts and sample line numbers of unparsable, duplicate and truncated rows,
+    /// and of --check-country-bbox findings, to this file, as JSON for a .json file and
+    /// CSV otherwise. Unparsable rows other than a truncated end are only counted with
+    /// --continue-on-error
+    #[arg(long)]
+    report_file: Option<PathBuf>,
+    /// Skip rows with the same country, city, region and rounded coordinates as an
+    /// earlier row of the same input; keeps one key per distinct row in memory
+    #[arg(long)]
+    dedupe_input: bool,
+    /// Number of decimal places the coordinates are rounded to for --dedupe-input
+    #[arg(long, default_value_t = 4)]
+    dedupe_precision: i32,
+    /// Skip rows whose country, city and region are in the table already, checked with
+    /// one query per batch, so appending the same file twice is harmless without a unique
+    /// constraint. An index on (country, city) keeps the check fast on large tables
+    #[arg(long, conflicts_with_all = ["sql_out", "conflict_on"])]
+    skip_existing: bool,
+    /// Additional point of every city stored in city_points, as
+    /// "KIND=LATITUDE_COLUMN,LONGITUDE_COLUMN" with header names of the input. Can be
+    /// repeated, a row with an empty coordinate gets no point of that kind. Batches with
+    /// points are sent as with --insert-mode unnest, which pairs them with their cities
+    #[arg(long = "extra-point")]
+    extra_points: Vec<ExtraPoint>,
+    /// Keep every parsed row with this probability, e.g. 0.1 for a dev data set of about
+    /// a tenth of the input spread over the whole file
+    #[arg(long, value_parser = parse_sample_rate)]
+    sample_rate: Option<f64>,
+    /// Load only rows of these country codes, e.g. fr,de,it, compared ignoring case; can
+    /// be repeated. Other rows are left out before they're batched
+    #[arg(long, value_delimiter = ',')]
+    only_countries: Vec<String>,
+    /// Count cities lying outside the bounding box of their country's boundary from
+    /// UploadCountries, a heuristic for swapped or wrong coordinates. They're still loaded,
+    /// and listed under outside_country_bbox in --report-file. Needs --srid 4326
+    #[arg(long)]
+    check_country_bbox: bool,
+    /// Degrees a city may lie outside the bounding box of its country for
+    /// --check-country-bbox
+    #[arg(long, default_value_t = 1.0, value_parser = parse_margin_degrees, requires = "check_country_bbox")]
+    country_bbox_margin: f64,
+    /// Seed of --sample-rate, the same seed keeps the same rows of the same input
+    #[arg(long, default_value_t = 0, requires = "sample_rate")]
+    seed: u64,
+}
+
+fn parse_sample_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(rate),
+        _ => Err(format!("expected a number in (0, 1], got \"{}\"", s)),
+    }
+}
+
+fn parse_margin_degrees(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(margin) if (0.0..=180.0).contains(&margin) => Ok(margin),
+        _ => Err(format!("expected degrees in [0, 180], got \"{}\"", s)),
+    }
+}
+
+fn parse_sha256(s: &str) -> Result<String, String> {
+    if s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit()) {
+        Ok(s.to_ascii_lowercase())
+    } else {
+        Err(format!("expected 64 hex digits, got \"{}\"", s))
+    }
+}
+
+/// BCP 47 language tag like "fr" or "de-CH", later looked up as an ICU collation.
+fn parse_locale(s: &str) -> Result<String, String> {
+    let valid = s
+        .split('-')
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()));
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("\"{}\" is not a language tag like fr or de-CH", s))
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("expected a number in [0, 1], got \"{}\"", s)),
+    }
+}
+
+/// Coordinate columns of an additional point type, see `--extra-point`.
+#[derive(Clone)]
+struct ExtraPoint {
+    kind: String,
+    latitude: String,
+    longitude: String,
+}
+
+impl FromStr for ExtraPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected \"kind=latitude,longitude\", got \"{}\"", s);
+        let (kind, columns) = s.split_once('=').ok_or_else(expected)?;
+        let (latitude, longitude) = columns.split_once(',').ok_or_else(expected)?;
+        if kind.is_empty() {
+            return Err(expected());
+        }
+        Ok(ExtraPoint {
+            kind: kind.to_string(),
+            latitude: latitude.trim().to_string(),
+            longitude: longitude.trim().to_string(),
+        })
+    }
+}
+
+/// Kind and positions of the latitude and longitude column of every `--extra-point`.
+fn extra_point_columns<'a>(
+    mapping: &columns::ColumnMapping,
+    extra_points: &'a [ExtraPoint],
+) -> Result<Vec<(&'a str, usize, usize)>, String> {
+    if !extra_points.is_empty() && mapping.original.is_none() {
+        return Err("--extra-point needs an input with a header".to_string());
+    }
+    extra_points
+        .iter()
+        .map(|p| {
+            let column = |name: &str| {
+                mapping
+                    .unmapped_column(name)
+                    .ok_or_else(|| format!("no unmapped column {} for {} points", name, p.kind))
+            };
+            Ok((p.kind.as_str(), column(&p.latitude)?, column(&p.longitude)?))
+        })
+        .collect()
+}
+
+/// Additional points of a row, rows with an empty coordinate get no point of that kind.
+fn extra_points<'a>(
+    row: &StringRecord,
+    columns: &[(&'a str, usize, usize)],
+    srid: u32,
+) -> Result<Vec<(&'a str, Point)>, String> {
+    let mut points = Vec::new();
+    for &(kind, lat, lon) in columns {
+        let (lat, lon) = (row.get(lat).unwrap_or(""), row.get(lon).unwrap_or(""));
+        if lat.trim().is_empty() || lon.trim().is_empty() {
+            continue;
+        }
+        let coordinate = |v: &str| {
+            v.trim()
+                .parse::<f64>()
+                .map_err(|e| format!("bad {} coordinate \"{}\": {}", kind, v, e))
+        };
+        points.push((
+            kind,
+            Point::new(coordinate(lon)?, coordinate(lat)?, Some(srid)),
+        ));
+    }
+    Ok(points)
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    #[command(flatten)]
+    csv: CsvArgs,
+    /// Coordinate difference in degrees up to which a city counts as unchanged
+    #[arg(long, default_value_t = 1e-6)]
+    tolerance: f64,
+}
+
+/// Input file and CSV dialect, shared by the commands reading city files.
+#[derive(Args)]
+struct CsvArgs {
+    /// Input file; .zip, .gz, .bz2 and .xz files are decompressed, anything else is read as CSV
+    #[arg(long, default_value = "./data/cities.txt.zip", env = "CITIES_INPUT")]
+    input: PathBuf,
+    /// The input has no header row, columns are taken by position in the order country,
+    /// city, accent city, region, latitude, longitude, population. Without it the header is
+    /// detected from its column names
+    #[arg(long)]
+    no_header: bool,
+    /// Treat a first row with no known column names as header anyway if its latitude or
+    /// longitude column isn't numeric, so a header with unexpected labels is skipped
+    /// instead of failing as data. The columns are then taken by position
+    #[arg(long, conflicts_with = "no_header")]
+    detect_header: bool,
+    /// Value meaning NULL in the optional columns region, latitude, longitude and
+    /// population, e.g. '\N', NULL or NA; repeat for several. Blank cells are NULL anyway
+    #[arg(long = "null-token")]
+    null_tokens: Vec<String>,
+    /// What a blank region cell is loaded as: NULL like a missing region column, or an
+    /// empty string to tell cities known to have no region apart from unknown ones
+    #[arg(long, value_enum, default_value_t = EmptyRegion::Null)]
+    empty_region_as: EmptyRegion,
+    /// Discard this many lines, e.g. license text, before the header or the first row.
+    /// Line numbers in error messages count from the first line after them
+    #[arg(long, default_value_t = 0)]
+    skip_header_rows: usize,
+    /// Field delimiter of the input
+    #[arg(long, default_value = ",", value_parser = parse_csv_byte)]
+    delimiter: u8,
+    /// Tab separated input without quoting, e.g. raw GeoNames dumps or Export --tsv output
+    #[arg(long, conflicts_with_all = ["delimiter", "quote", "escape"])]
+    tsv: bool,
+    /// Quote character of the CSV input
+    #[arg(long, default_value = "\"", value_parser = parse_csv_byte)]
+    quote: u8,
+    /// Escape character for quotes inside quoted fields, e.g. '\'
+    #[arg(long, value_parser = parse_csv_byte)]
+    escape: Option<u8>,
+    /// Allow records with a varying number of fields
+    #[arg(long)]
+    flexible: bool,
+    /// Trim text fields and collapse internal runs of whitespace; exact-match name and
+    /// country lookups then match the normalized values rather than the raw input
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    trim: bool,
+    /// Whitespace the CSV parser strips around the header names and the fields, before
+    /// anything else sees them. The header row is read like a data row, so fields and all
+    /// both trim it too
+    #[arg(long, value_enum, default_value_t = CsvTrim::None)]
+    csv_trim: CsvTrim,
+    /// Load the complete rows of a truncated input instead of failing
+    #[arg(long)]
+    allow_truncated: bool,
+    /// Size of the read buffers of the input file, the decompressed stream and the CSV
+    /// parser; larger buffers mean fewer read calls on big files
+    #[arg(long, default_value_t = 64 * 1024, value_parser = clap::value_parser!(u32).range(1..), env = "CITIES_READ_BUFFER_BYTES")]
+    read_buffer_bytes: u32,
+    /// Decompress the data file of a .zip input on a second thread while it's parsed,
+    /// instead of extracting it to the current directory first
+    #[arg(long)]
+    stream_zip: bool,
+    /// Read the input as a stream which can be read only once, e.g. a named pipe another
+    /// process writes into: it isn't hashed before the load, and a .zip input is read
+    /// entry by entry instead of being extracted. Named pipes are detected without it
+    #[arg(long)]
+    pipe: bool,
+}
+
+impl CsvArgs {
+    fn is_pipe(&self) -> bool {
+        self.pipe || is_fifo(&self.input)
+    }
+}
+
+/// Whether the path is a named pipe, which can't be seeked or read a second time.
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+fn parse_csv_byte(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        [b] if b.is_ascii() => Ok(*b),
+        _ => Err(format!("expected a single ASCII character, got \"{}\"", s)),
+    }
+}
+
+#[derive(Args)]
+struct NearestArgs {
+    #[arg(long, allow_negative_numbers = true)]
+    lat: f64,
+    #[arg(long, allow_negative_numbers = true)]
+    lon: f64,
+    /// Number of cities to return
+    #[arg(long, default_value_t = 10)]
+    limit: i64,
+    /// Units of the printed distance
+    #[arg(long, value_enum, default_value_t = Units::Km)]
+    units: Units,
+    /// Return only cities within this distance, possibly fewer than --limit. Meters,
+    /// or degrees with --metric 2d
+    #[arg(long)]
+    max_distance: Option<f64>,
+    /// Distance to print, filter and order by. Without it, cities are ordered by the
+    /// planar index distance and the sphere distance is printed. sphere and spheroid
+    /// ordering can't use the index, so they compute the distance of every city unless
+    /// --max-distance narrows them down
+    #[arg(long, value_enum)]
+    metric: Option<Metric>,
+    /// Skip this many nearest cities
+    #[arg(long, default_value_t = 0)]
+    offset: i64,
+    /// Continue after the cursor printed with the previous page. Unlike --offset, pages
+    /// don't shift when cities are inserted or deleted in between
+    #[arg(long, conflicts_with = "offset")]
+    after: Option<Cursor>,
+    /// Return only the nearest city for every name
+    #[arg(long, conflicts_with_all = ["max_distance", "offset", "after", "metric"])]
+    distinct_names: bool,
+    /// With --distinct-names, how many nearest cities are deduplicated per requested city
+    #[arg(long, default_value_t = 20)]
+    candidates_factor: i64,
+    /// Print a GeoJSON FeatureCollection with the city attributes and distance as
+    /// properties; the distance is in meters, or degrees with --metric 2d
+    #[arg(long)]
+    geojson: bool,
+    /// Indent the JSON output instead of printing it on one line
+    #[arg(long, requires = "geojson")]
+    pretty: bool,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+    /// Repeat the queries with index scans disabled and compare percentiles
+    #[arg(long)]
+    compare: bool,
+    /// Benchmark inserts of random cities instead of queries. They are rolled back at the
+    /// end, so the cost of the final commit isn't measured
+    #[arg(long, conflicts_with = "compare")]
+    write: bool,
+    /// Print the point of every query taking longer than this many milliseconds
+    #[arg(long)]
+    slow_threshold_ms: Option<u64>,
+    /// Stop the queries after this many seconds and report the ones finished so far, the
+    /// query running at that point is cancelled. With --compare both runs share the budget
+    #[arg(long)]
+    max_duration: Option<u64>,
+    /// Number of cities inserted by --write
+    #[arg(long, default_value_t = 100_000)]
+    rows: usize,
+    /// Number of cities per insert batch of --write
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    batch_size: u32,
+    /// How --write sends the batches
+    #[arg(long, value_enum, default_value_t = InsertMode::Values)]
+    insert_mode: InsertMode,
+    /// Seed of the random cities, the same seed generates the same workload
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+#[derive(Args)]
+struct TuneArgs {
+    #[command(flatten)]
+    csv: CsvArgs,
+    /// Number of input rows loaded with every batch size
+    #[arg(long, default_value_t = 100_000)]
+    rows: usize,
+    /// Batch sizes to compare
+    #[arg(long, value_delimiter = ',', default_value = "500,1000,5000,10000,50000", value_parser = clap::value_parser!(u32).range(1..))]
+    batch_sizes: Vec<u32>,
+    #[arg(long, value_enum, default_value_t = InsertMode::Values)]
+    insert_mode: InsertMode,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum InsertMode {
+    /// Multi-row INSERT ... VALUES statement per batch
+    Values,
+    /// INSERT ... SELECT FROM unnest(...) with one array bind per column
+    Unnest,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum IndexStrategy {
+    /// Keep the index and update it with every insert: always queryable with the index,
+    /// the slowest inserts
+    During,
+    /// Drop the index before the first batch and build it once after the last: the
+    /// fastest load, without index until it's done
+    After,
+    /// Drop the index before every --index-every batches and build it after them: the
+    /// table is completely indexed at each interval, but every build reads the whole
+    /// table, so builds get slower as it grows and frequent ones cost more than they save
+    Periodic,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Csv,
+    /// One JSON object per line, for jq or document stores
+    Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExplainFormat {
+    /// Status line, followed by the plan if the index isn't used
+    Text,
+    /// Plan of EXPLAIN (FORMAT JSON) only
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EmptyRegion {
+    Null,
+    Empty,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CsvTrim {
+    None,
+    Headers,
+    Fields,
+    All,
+}
+
+impl From<CsvTrim> for csv::Trim {
+    fn from(trim: CsvTrim) -> Self {
+        match trim {
+            CsvTrim::None => csv::Trim::None,
+            CsvTrim::Headers => csv::Trim::Headers,
+            CsvTrim::Fields => csv::Trim::Fields,
+            CsvTrim::All => csv::Trim::All,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Units {
+    /// Kilometers
+    Km,
+    /// Miles
+    Mi,
+}
+
+/// Prints JSON output of a query, compact for other tools or indented with --pretty.
+fn print_json(value: &serde_json::Value, pretty: bool) {
+    if pretty {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+    } else {
+        println!("{}", value);
+    }
+}
+
+/// Formats a distance given in meters, rounded to 0.1 of the requested units.
+fn format_distance(meters: f64, units: Units) -> String {
+    match units {
+        Units::Km => format!("{:.1} km", meters / 1000.0),
+        Units::Mi => format!("{:.1} mi", meters / 1609.344),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LatLon {
+    lat: f64,
+    lon: f64,
+}
+
+impl FromStr for LatLon {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (lat, lon) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected \"lat,lon\", got \"{}\"", s))?;
+        let lat: f64 = lat
+            .trim()
+            .parse()
+            .map_err(|e| format!("bad latitude: {}", e))?;
+        let lon: f64 = lon
+            .trim()
+            .parse()
+            .map_err(|e| format!("bad longitude: {}", e))?;
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(format!("point {},{} is out of range", lat, lon));
+        }
+        Ok(LatLon { lat, lon })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn run_sqlite(cli: &Cli) {
+    match &cli.command {
+        Commands::Upload(args) => sqlite::upload(&cli.sqlite_path, args),
+        Commands::Nearest(args)
+            if !args.distinct_names
+                && args.after.is_none()
+                && args.metric.is_none()
+                && !args.geojson =>
+        {
+            sqlite::nearest(&cli.sqlite_path, args)
+        }
+        _ => {
+            eprintln!(
+                "--backend sqlite supports only Upload and Nearest without \
+                 --distinct-names, --after, --metric and --geojson"
+            );
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn run_sqlite(_cli: &Cli) {
+    eprintln!("--backend sqlite needs a build with the sqlite feature");
+    process::exit(2);
+}
+
+#[cfg(feature = "serve")]
+fn run_serve(c_pool: Pool<ConnectionManager<PgConnection>>, bind: &str, port: u16) {
+    serve::run(c_pool, bind, port);
+}
+
+#[cfg(not(feature = "serve"))]
+fn run_serve(_c_pool: Pool<ConnectionManager<PgConnection>>, _bind: &str, _port: u16) {
+    eprintln!("Serve needs a build with the serve feature");
+    process::exit(2);
+}
+
+fn main() {
+    let cli = Cli::parse();
+    VERBOSE.store(cli.verbose, Ordering::Relaxed);
+
+    let geometry_column = cli.geometry_column.as_str();
+    // commands built on the diesel query builder can't use another geometry column
+    let uses_static_schema = match &cli.command {
+        Commands::Upload(args) => {
+            args.sql_out.is_none() && matches!(args.insert_mode, InsertMode::Values)
+        }
+        Commands::Bench(_)
+        | Commands::Tune(_)
+        | Commands::Serve { .. }
+        | Commands::Nearest(_)
+        | Commands::Repl { .. }
+        | Commands::Diff(_) => true,
+        _ => false,
+    };
+    if geometry_column != DEFAULT_GEOMETRY_COLUMN && uses_static_schema {
+        eprintln!("--geometry-column is not supported by this command, only by raw SQL paths");
+        process::exit(2);
+    }
+
+    if let Commands::Generate {
+        count,
+        output,
+        seed,
+    } = &cli.command
+    {
+        generate(*count, output, *seed);
+        return;
+    }
+    if let Commands::DumpSchema = cli.command {
+        dump_schema::print();
+        return;
+    }
+    if let Commands::DistanceMatrix {
+        points,
+        file,
+        distance_impl,
+    } = &cli.command
+    {
+        if let Some(distance) = distance_impl.client() {
+            let points = matrix_points(points, file.as_deref());
+            print_distance_matrix(&points, client_distance_matrix(&points, distance));
+            return;
+        }
+    }
+    if let Backend::Sqlite = cli.backend {
+        run_sqlite(&cli);
+        return;
+    }
+    if let Commands::Upload(args) = &cli.command {
+        // the script is for databases this process can't reach, so it doesn't connect
+        if let Some(path) = &args.sql_out {
+            insert_data(UploadTarget::SqlFile(path), args, geometry_column);
+            return;
+        }
+    }
+    let database_url = database_url(
+        cli.dotenv_path.as_deref(),
+        cli.connection_url_file.as_deref(),
+    );
+    if let Err(e) = validate_database_url(&database_url) {
+        eprintln!("invalid connection URL: {}", e);
+        process::exit(1);
+    }
+    if let Commands::Ping = cli.command {
+        // without the pool, it would retry until its connection timeout
+        process::exit(ping(&database_url, &cli.app_name));
+    }
+    let c_pool = init_connection_pool(database_url, &cli);
+    let queries_cities = match &cli.command {
+        Commands::Bench(args) => !args.write,
+        Commands::Nearest(_)
+        | Commands::Serve { .. }
+        | Commands::NearestBatch { .. }
+        | Commands::ClosestPair { .. }
+        | Commands::Repl { .. } => true,
+        _ => false,
+    };
+    if cli.strict_srid && queries_cities {
+        check_srid(&c_pool, geometry_column);
+    }
+    match cli.command {
+        Commands::Ping | Commands::Generate { .. } | Commands::DumpSchema => unreachable!(),
+        Commands::DiskUsage => disk_usage(&c_pool, geometry_column),
+        Commands::Extent {
+            country,
+            antimeridian_margin,
+        } => extent(
+            &c_pool,
+            country.as_deref(),
+            antimeridian_margin,
+            geometry_column,
+        ),
+        Commands::IndexCheck {
+            disable_seqscan,
+            explain_format,
+        } => process::exit(index_check(
+            &c_pool,
+            disable_seqscan,
+            explain_format,
+            geometry_column,
+        )),
+        Commands::IntegrityCheck => process::exit(integrity::check(&c_pool)),
+        Commands::Repl {
+            units,
+            cache_size,
+            distance_impl,
+        } => repl::run(c_pool, units, cache_size, distance_impl),
+        Commands::Reindex {
+            table,
+            concurrently,
+        } => reindex(&c_pool, table, concurrently, geometry_column),
+        Commands::Upload(args) => {
+            insert_data(UploadTarget::Database(&c_pool), &args, geometry_column)
+        }
+        Commands::Bench(args) if args.write => bench_insert(&c_pool, &args),
+        Commands::Bench(args) => bench_get(&c_pool, &args),
+        Commands::Tune(args) => tune(&c_pool, &args),
+        Commands::MajorCities {
+            min_population,
+            refresh,
+        } => major_cities(&c_pool, min_population, refresh, geometry_column),
+        Commands::Nearest(args) => print_nearest(&c_pool, &args),
+        Commands::Serve { port, bind } => run_serve(c_pool, &bind, port),
+        Commands::Export {
+            format,
+            delimiter,
+            tsv,
+            since,
+            locale,
+            chunk_size,
+            resume_from_id,
+            output_file,
+        } => export(
+            &c_pool,
+            format,
+            delimiter,
+            tsv,
+            since,
+            locale.as_deref(),
+            chunk_size,
+            resume_from_id,
+            output_file.as_deref(),
+        ),
+        Commands::Diff(args) => diff(&c_pool, &args),
+        Commands::Backfill {
+            column,
+            geohash_precision,
+            rank_expression,
+            all,
+            batch_size,
+            resume_from_id,
+        } => backfill(
+            &c_pool,
+            column,
+            column.expression(geohash_precision, &rank_expression, geometry_column),
+            all,
+            batch_size,
+            resume_from_id,
+        ),
+        Commands::UploadCountries {
+            input,
+            name_property,
+            code_property,
+        } => countries::upload(&c_pool, &input, &name_property, &code_property),
+        Commands::CountryOf { lat, lon } => process::exit(countries::country_of(&c_pool, lat, lon)),
+        Commands::BuildLod { levels } => build_lod(&c_pool, &levels, geometry_column),
+        Commands::BuildRegionCentroids => build_region_centroids(&c_pool, geometry_column),
+        Commands::Analyze { check } => analyze::run(&c_pool, check, geometry_column),
+        Commands::ClosestPair {
+            top,
+            country,
+            units,
+        } => closest_pairs(&c_pool, top, country.as_deref(), units, geometry_column),
+        Commands::NearestBatch {
+            input_points_csv,
+            lat_column,
+            lon_column,
+            batch_size,
+            output_file,
+        } => nearest_batch::run(
+            &c_pool,
+            &input_points_csv,
+            &lat_column,
+            &lon_column,
+            batch_size as usize,
+            geometry_column,
+            output_file.as_deref(),
+        ),
+        Commands::PruneBbox {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+            invert,
+            dry_run,
+        } => prune_bbox(
+            &c_pool,
+            LatLon {
+                lat: min_lat,
+                lon: min_lon,
+            },
+            LatLon {
+                lat: max_lat,
+                lon: max_lon,
+            },
+            invert,
+            dry_run,
+            geometry_column,
+        ),
+        Commands::LoadHistory { limit } => load_runs::print_history(&c_pool, limit),
+        Commands::DistanceMatrix { points, file, .. } => {
+            let points = matrix_points(&points, file.as_deref());
+            print_distance_matrix(&points, db_distance_matrix(&c_pool, &points));
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct Exists {
+    #[diesel(sql_type = Bool)]
+    exists: bool,
+}
+
+fn one_line(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Prints a one line status and returns the process exit code.
+fn ping(database_url: &str, app_name: &str) -> i32 {
+    let mut conn = match PgConnection::establish(database_url) {
+        Ok(conn) => conn,
+        Err(e) => {
+            println!("error: can't connect: {}", one_line(&e.to_string()));
+            return 1;
+        }
+    };
+    if let Err(e) = ApplicationName(app_name.to_string()).apply(&mut conn) {
+        println!(
+            "error: can't set application_name: {}",
+            one_line(&e.to_string())
+        );
+        return 1;
+    }
+    if let Err(e) = diesel::sql_query("SELECT 1").execute(&mut conn) {
+        println!("error: can't query: {}", one_line(&e.to_string()));
+        return 1;
+    }
+    let cities = diesel::sql_query("SELECT to_regclass('cities') IS NOT NULL AS exists")
+        .get_result::<Exists>(&mut conn);
+    match cities {
+        Ok(Exists { exists: true }) => {
+            println!("ok");
+            0
+        }
+        Ok(Exists { exists: false }) => {
+            println!("error: cities table doesn't exist, run Upload first");
+            1
+        }
+        Err(e) => {
+            println!("error: can't check schema: {}", one_line(&e.to_string()));
+            1
+        }
+    }
+}
+
+/// Name of the GiST index of cities on `geometry_column`, named after the column like
+/// the cities_location_idx of the migrations.
+fn location_index(geometry_column: &str) -> String {
+    format!("cities_{}_idx", geometry_column)
+}
+
+/// Nearest neighbor query IndexCheck plans.
+fn nearest_check_query(geometry_column: &str) -> String {
+    format!(
+        "SELECT id FROM cities ORDER BY \"{}\" <-> ST_SetSRID(ST_MakePoint(0, 0), 4326) LIMIT 10",
+        geometry_column
+    )
+}
+
+#[derive(QueryableByName)]
+struct PlanLine {
+    #[diesel(column_name = "QUERY PLAN", sql_type = Text)]
+    line: String,
+}
+
+#[derive(QueryableByName)]
+struct PlanJson {
+    #[diesel(column_name = "QUERY PLAN", sql_type = Json)]
+    plan: serde_json::Value,
+}
+
+/// Whether the node of a JSON plan or one of its children scans `index`.
+fn plan_uses_index(node: &serde_json::Value, index: &str) -> bool {
+    node["Index Name"] == index
+        || node["Plans"]
+            .as_array()
+            .is_some_and(|plans| plans.iter().any(|p| plan_uses_index(p, index)))
+}
+
+/// Prints a one line status, the plan too if it doesn't use the index, or only the JSON
+/// plan, and returns the process exit code.
+fn index_check(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    disable_seqscan: bool,
+    explain_format: ExplainFormat,
+    geometry_column: &str,
+) -> i32 {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let location_index = location_index(geometry_column);
+    let index = diesel::sql_query(
+        "SELECT EXISTS (SELECT 1 FROM pg_indexes \
+                        WHERE tablename = 'cities' AND indexname = $1) AS exists",
+    )
+    .bind::<Text, _>(&location_index)
+    .get_result::<Exists>(&mut conn)
+    .expect("can't query indexes");
+    if !index.exists {
+        println!("error: index {} doesn't exist", location_index);
+        return 1;
+    }
+    let plan_query = |conn: &mut PgConnection, explain: &str| {
+        if disable_seqscan {
+            diesel::sql_query("SET LOCAL enable_seqscan = off").execute(conn)?;
+        }
+        Ok::<_, diesel::result::Error>(diesel::sql_query(format!(
+            "{} {}",
+            explain,
+            nearest_check_query(geometry_column)
+        )))
+    };
+    if let ExplainFormat::Json = explain_format {
+        let plan = conn
+            .transaction(|conn| {
+                plan_query(conn, "EXPLAIN (FORMAT JSON)")?.get_result::<PlanJson>(conn)
+            })
+            .expect("can't explain nearest query")
+            .plan;
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&plan).expect("can't write plan")
+        );
+        // a single statement has a single plan
+        if plan_uses_index(&plan[0]["Plan"], &location_index) {
+            return 0;
+        }
+        eprintln!("error: nearest query doesn't use {}", location_index);
+        return 1;
+    }
+    let plan = conn
+        .transaction(|conn| plan_query(conn, "EXPLAIN")?.load::<PlanLine>(conn))
+        .expect("can't explain nearest query");
+    let scan = format!("Scan using {} on cities", location_index);
+    if plan.iter().any(|l| l.line.contains(&scan)) {
+        println!("ok");
+        return 0;
+    }
+    println!("error: nearest query doesn't use {}:", location_index);
+    for l in plan {
+        println!("{}", l.line);
+    }
+    1
+}
+
+#[derive(QueryableByName)]
+struct Size {
+    #[diesel(sql_type = Int8)]
+    size: i64,
+}
+
+fn reindex(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    table: bool,
+    concurrently: bool,
+    geometry_column: &str,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let (kind, name, size_fn) = if table {
+        ("TABLE", "cities".to_string(), "pg_indexes_size")
+    } else {
+        ("INDEX", location_index(geometry_column), "pg_relation_size")
+    };
+    let index_size = |conn: &mut PgConnection| {
+        diesel::sql_query(format!("SELECT {}($1::regclass) AS size", size_fn))
+            .bind::<Text, _>(&name)
+            .get_result::<Size>(conn)
+            .expect("can't get index size")
+            .size
+    };
+    let before = index_size(&mut conn);
+    let start = SystemTime::now();
+    diesel::sql_query(format!(
+        "REINDEX {} {}{}",
+        kind,
+        if concurrently { "CONCURRENTLY " } else { "" },
+        name
+    ))
+    .execute(&mut conn)
+    .expect("can't reindex");
+    let after = index_size(&mut conn);
+    println!(
+        "reindexed {} in {:?}, index size {} -> {}",
+        name,
+        start.elapsed().unwrap(),
+        format_bytes(before),
+        format_bytes(after)
+    );
+}
+
+#[derive(QueryableByName)]
+struct Extent {
+    #[diesel(sql_type = Nullable<Float8>)]
+    min_lon: Option<f64>,
+    #[diesel(sql_type = Nullable<Float8>)]
+    min_lat: Option<f64>,
+    #[diesel(sql_type = Nullable<Float8>)]
+    max_lon: Option<f64>,
+    #[diesel(sql_type = Nullable<Float8>)]
+    max_lat: Option<f64>,
+}
+
+#[derive(QueryableByName)]
+struct AntimeridianCounts {
+    /// Cities just west of the antimeridian, at longitudes close to 180.
+    #[diesel(sql_type = Int8)]
+    west: i64,
+    /// Cities just east of it, at longitudes close to -180.
+    #[diesel(sql_type = Int8)]
+    east: i64,
+}
+
+fn extent(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    country: Option<&str>,
+    antimeridian_margin: f64,
+    geometry_column: &str,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let query = diesel::sql_query(format!(
+        "SELECT ST_XMin(e) AS min_lon, ST_YMin(e) AS min_lat, \
+                ST_XMax(e) AS max_lon, ST_YMax(e) AS max_lat \
+         FROM (SELECT ST_Extent(\"{}\") AS e FROM cities \
+               WHERE $1::text IS NULL OR country ILIKE $1) extent",
+        geometry_column
+    ))
+    .bind::<Nullable<Text>, _>(country);
+    log_query(&query);
+    let extent: Extent = query.get_result(&mut conn).expect("can't query extent");
+    let (Some(min_lon), Some(min_lat), Some(max_lon), Some(max_lat)) = (
+        extent.min_lon,
+        extent.min_lat,
+        extent.max_lon,
+        extent.max_lat,
+    ) else {
+        println!("no cities");
+        return;
+    };
+    println!("min\t{:.6}, {:.6}", min_lat, min_lon);
+    println!("max\t{:.6}, {:.6}", max_lat, max_lon);
+    println!(
+        "center\t{:.6}, {:.6}",
+        (min_lat + max_lat) / 2.0,
+        (min_lon + max_lon) / 2.0
+    );
+
+    let query = diesel::sql_query(format!(
+        "SELECT count(*) FILTER (WHERE ST_X(\"{col}\") >= 180 - $2) AS west, \
+                count(*) FILTER (WHERE ST_X(\"{col}\") <= -180 + $2) AS east \
+         FROM cities \
+         WHERE ($1::text IS NULL OR country ILIKE $1) \
+           AND (ST_X(\"{col}\") >= 180 - $2 OR ST_X(\"{col}\") <= -180 + $2)",
+        col = geometry_column
+    ))
+    .bind::<Nullable<Text>, _>(country)
+    .bind::<Float8, _>(antimeridian_margin);
+    log_query(&query);
+    let counts: AntimeridianCounts = query
+        .get_result(&mut conn)
+        .expect("can't count cities near the antimeridian");
+    println!(
+        "antimeridian\t{} cities within {}° west, {} within {}° east of it",
+        counts.west, antimeridian_margin, counts.east, antimeridian_margin
+    );
+    if counts.west > 0 && counts.east > 0 {
+        eprintln!(
+            "warning: there are cities on both sides of the antimeridian. The extent above \
+             spans all longitudes between them the long way round, and bounding boxes \
+             across it have to be split in two, e.g. for PruneBbox"
+        );
+    }
+}
+
+fn disk_usage(c_pool: &Pool<ConnectionManager<PgConnection>>, geometry_column: &str) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let mut size = |size_fn: &str, relation: &str| {
+        diesel::sql_query(format!("SELECT {}($1::regclass) AS size", size_fn))
+            .bind::<Text, _>(relation)
+            .get_result::<Size>(&mut conn)
+            .unwrap_or_else(|e| panic!("can't get size of {}: {}", relation, e))
+            .size
+    };
+    let total = size("pg_total_relation_size", "cities");
+    let heap = size("pg_relation_size", "cities");
+    let indexes = size("pg_indexes_size", "cities");
+    let location_index_name = location_index(geometry_column);
+    let location_index = size("pg_relation_size", &location_index_name);
+    let rows = [
+        ("cities total".to_string(), total),
+        ("  heap".to_string(), heap),
+        (format!("  {}", location_index_name), location_index),
+        ("  other indexes".to_string(), indexes - location_index),
+        // TOAST of long raw values plus the free space and visibility maps
+        ("  toast and maps".to_string(), total - heap - indexes),
+    ];
+    for (label, bytes) in rows {
+        println!("{:<24}{:>10}", label, format_bytes(bytes));
+    }
+}
+
+fn format_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn print_nearest(c_pool: &Pool<ConnectionManager<PgConnection>>, args: &NearestArgs) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let point = LatLon {
+        lat: args.lat,
+        lon: args.lon,
+    };
+    // one more city tells whether there is a next page
+    let mut neighbors = if args.distinct_names {
+        nearest_distinct_names(
+            &mut conn,
+            point,
+            args.limit + 1,
+            args.limit * args.candidates_factor,
+            args.geojson,
+        )
+    } else {
+        let options = NearestOptions {
+            max_distance: args.max_distance,
+            offset: args.offset,
+            after: args.after,
+            metric: args.metric,
+            geojson: args.geojson,
+        };
+        nearest(&mut conn, point, args.limit + 1, &options)
+    };
+    let has_more = neighbors.len() as i64 > args.limit;
+    neighbors.truncate(args.limit as usize);
+    if args.geojson {
+        let distance_key = match args.metric {
+            Some(Metric::Planar) => "distance_degrees",
+            _ => "distance_meters",
+        };
+        let features = neighbors
+            .iter()
+            .map(|n| {
+                let mut properties = geojson::city_properties(&n.city);
+                properties.insert(distance_key.to_string(), n.distance.into());
+                (n.geometry.as_deref(), properties)
+            })
+            .collect();
+        print_json(&geojson::feature_collection(features), args.pretty);
+        return;
+    }
+    for n in &neighbors {
+        let distance = match args.metric {
+            Some(Metric::Planar) => format!("{:.4}°", n.distance),
+            _ => format_distance(n.distance, args.units),
+        };
+        println!("{}\t{}", n.city, distance);
+    }
+    // the options conflict, a later page is continued the way it was reached and the
+    // first one either way
+    match neighbors.last() {
+        Some(last) if has_more && !args.distinct_names => match (args.after, args.offset) {
+            (Some(_), _) => println!("more results: --after {}", last.cursor()),
+            (None, 0) => println!(
+                "more results: --offset {} or --after {}",
+                args.limit,
+                last.cursor()
+            ),
+            (None, offset) => println!("more results: --offset {}", offset + args.limit),
+        },
+        _ => println!("no more results"),
+    }
+}
+
+/// City returned by a nearest neighbors query.
+#[derive(Clone, QueryableByName)]
+struct Neighbor {
+    #[diesel(embed)]
+    city: City,
+    /// Distance in the requested metric, sphere distance in meters by default.
+    #[diesel(sql_type = Float8)]
+    distance: f64,
+    /// Distance the query is ordered by, the planar `<->` distance in degrees by default.
+    #[diesel(sql_type = Float8)]
+    order_distance: f64,
+    /// ST_AsGeoJSON of the location, only selected with `NearestOptions::geojson`.
+    #[diesel(sql_type = Nullable<Text>)]
+    geometry: Option<String>,
+}
+
+impl Neighbor {
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            order_distance: self.order_distance,
+            id: self.city.id,
+        }
+    }
+}
+
+/// Position of a city in the nearest neighbors order, the ordering distance with id as
+/// tie-break. Only valid with the same --metric as the page it was printed with.
+#[derive(Clone, Copy, Debug)]
+struct Cursor {
+    order_distance: f64,
+    id: i64,
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // {:?} keeps every digit, so the cursor compares equal to the stored distance
+        write!(f, "{:?},{}", self.order_distance, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (distance, id) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected \"distance,id\", got \"{}\"", s))?;
+        Ok(Cursor {
+            order_distance: distance
+                .parse()
+                .map_err(|e| format!("bad distance: {}", e))?,
+            id: id.parse().map_err(|e| format!("bad id: {}", e))?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct NearestOptions {
+    /// Return only cities within this distance in the metric.
+    max_distance: Option<f64>,
+    offset: i64,
+    after: Option<Cursor>,
+    /// Distance to report, filter and order by. Without it, cities are ordered by the
+    /// index backed planar distance and the sphere distance is reported.
+    metric: Option<Metric>,
+    /// Select the locations as GeoJSON too.
+    geojson: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Metric {
+    /// ST_Distance, planar distance in degrees; ordered by the KNN index
+    #[value(name = "2d")]
+    Planar,
+    /// ST_DistanceSphere in meters, fast and good enough for most uses
+    Sphere,
+    /// ST_DistanceSpheroid on WGS 84 in meters, accurate but slow
+    Spheroid,
+}
+
+type DistanceExpression = Box<dyn BoxableExpression<cities::table, Pg, SqlType = Float8>>;
+
+/// Distance from the cities to `p` in the metric.
+fn metric_distance(metric: Metric, p: Point) -> DistanceExpression {
+    match metric {
+        Metric::Planar => Box::new(st_distance(cities::location, p)),
+        Metric::Sphere => Box::new(st_distance_sphere(cities::location, p)),
+        Metric::Spheroid => Box::new(st_distance_spheroid(p)),
+    }
+}
+
+/// ST_DistanceSpheroid from the city locations on WGS 84. The spheroid type has no
+/// diesel mapping, so it's inlined as a literal.
+fn st_distance_spheroid(
+    p: Point,
+) -> SqlLiteral<Float8, UncheckedBind<SqlLiteral<Float8>, AsExprOf<Point, Geometry>>> {
+    diesel::dsl::sql::<Float8>("ST_DistanceSpheroid(location, ")
+        .bind::<Geometry, _>(p)
+        .sql(", 'SPHEROID[\"WGS 84\",6378137,298.257223563]')")
+}
+
+/// Like `nearest`, but keeps only the nearest city for every name.
+///
+/// Postgres requires `DISTINCT ON (city)` to be ordered by `city` first, so it can't be
+/// combined with the index backed `<->` ordering directly. Instead the `candidates`
+/// nearest cities are taken from the index, deduplicated and sorted by distance again.
+/// When more than `candidates - limit` of them share names, fewer than `limit` cities
+/// are returned.
+fn nearest_distinct_names(
+    conn: &mut PgConnection,
+    point: LatLon,
+    limit: i64,
+    candidates: i64,
+    geojson: bool,
+) -> Vec<Neighbor> {
+    let query = diesel::sql_query(
+        "SELECT * FROM ( \
+             SELECT DISTINCT ON (city) * FROM ( \
+                 SELECT id, country, city, accent_city, region, location, population, \
+                        ST_DistanceSphere(location, $1) AS distance, \
+                        ST_Distance(location, $1) AS order_distance, \
+                        CASE WHEN $4 THEN ST_AsGeoJSON(location) END AS geometry \
+                 FROM cities ORDER BY location <-> $1, id LIMIT $2 \
+             ) candidates ORDER BY city, distance, id \
+         ) distinct_names ORDER BY distance, id LIMIT $3",
+    )
+    .bind::<Geometry, _>(Point::new(point.lon, point.lat, Some(4326)))
+    .bind::<Int8, _>(candidates)
+    .bind::<Int8, _>(limit)
+    .bind::<Bool, _>(geojson);
+    log_query(&query);
+    query.load(conn).expect("can't query nearest cities")
+}
+
+/// Returns up to `limit` cities nearest to the point.
+///
+/// With `max_distance` the query stays an index backed KNN scan:
+///
+/// ```sql
+/// SELECT ... FROM cities
+/// WHERE location && ST_MakeEnvelope(<box around the point>, 4326)
+///   AND ST_DistanceSphere(location, $p) <= $max_distance
+/// ORDER BY location <-> $p, id LIMIT $limit
+/// ```
+///
+/// The `&&` check is an index condition of the same GiST scan, so the scan stops at the
+/// edge of the box instead of walking the whole index when fewer than `limit` cities are
+/// within the distance.
+///
+/// Paging: a KNN index scan always starts at the nearest city, so both `offset` and
+/// `after` walk over all the skipped cities and deep pages get slower either way. OFFSET
+/// also computes the output columns of every skipped row and shifts when cities are
+/// inserted or deleted between pages; the `after` cursor filters the skipped rows out
+/// right in the scan and keeps pages stable.
+fn nearest(
+    conn: &mut PgConnection,
+    point: LatLon,
+    limit: i64,
+    options: &NearestOptions,
+) -> Vec<Neighbor> {
+    let p = Point::new(point.lon, point.lat, Some(4326));
+    let reported = options.metric.unwrap_or(Metric::Sphere);
+    let ordered = options.metric.unwrap_or(Metric::Planar);
+    let mut query = cities::table
+        .select((
+            City::as_select(),
+            metric_distance(reported, p),
+            metric_distance(ordered, p),
+            diesel::dsl::sql::<Nullable<Text>>(if options.geojson {
+                "ST_AsGeoJSON(location)"
+            } else {
+                "NULL"
+            }),
+        ))
+        .limit(limit)
+        .offset(options.offset)
+        .into_boxed();
+    query = match ordered {
+        Metric::Planar => {
+            query.order_by(postgis_diesel::operators::distance_2d(cities::location, p))
+        }
+        // ordering by a function result can't use the index, only --max-distance keeps
+        // these from computing the distance of every city
+        Metric::Sphere => query.order_by(st_distance_sphere(cities::location, p)),
+        Metric::Spheroid => query.order_by(st_distance_spheroid(p)),
+    }
+    .then_order_by(cities::id.asc());
+    if let Some(max_distance) = options.max_distance {
+        let (min, max) = match reported {
+            Metric::Planar => planar_box(point, max_distance),
+            // the spheroid distance is up to 0.5% longer than the sphere distance
+            Metric::Sphere => search_box(point, max_distance),
+            Metric::Spheroid => search_box(point, max_distance * 1.005),
+        };
+        query = query
+            .filter(postgis_diesel::operators::intersects_2d(
+                cities::location,
+                st_make_envelope(min.lon, min.lat, max.lon, max.lat, 4326),
+            ))
+            .filter(metric_distance(reported, p).le(max_distance));
+    }
+    if let Some(after) = options.after {
+        query = query.filter(
+            metric_distance(ordered, p)
+                .gt(after.order_distance)
+                .or(metric_distance(ordered, p)
+                    .eq(after.order_distance)
+                    .and(cities::id.gt(after.id))),
+        );
+    }
+    log_query(&query);
+    query
+        .load::<(City, f64, f64, Option<String>)>(conn)
+        .expect("can't query nearest cities")
+        .into_iter()
+        .map(|(city, distance, order_distance, geometry)| Neighbor {
+            city,
+            distance,
+            order_distance,
+            geometry,
+        })
+        .collect()
+}
+
+/// Box of `degrees` around the point, clamped to the valid coordinates.
+fn planar_box(point: LatLon, degrees: f64) -> (LatLon, LatLon) {
+    (
+        LatLon {
+            lat: (point.lat - degrees).max(-90.0),
+            lon: (point.lon - degrees).max(-180.0),
+        },
+        LatLon {
+            lat: (point.lat + degrees).min(90.0),
+            lon: (point.lon + degrees).min(180.0),
+        },
+    )
+}
+
+/// Returns corners of a lat/lon box containing every point within `meters` of the point.
+fn search_box(point: LatLon, meters: f64) -> (LatLon, LatLon) {
+    let dlat = (meters / distance::MEAN_RADIUS).to_degrees();
+    let min_lat = (point.lat - dlat).max(-90.0);
+    let max_lat = (point.lat + dlat).min(90.0);
+    // a degree of longitude is shortest at the latitude farthest from the equator
+    let cos = min_lat.abs().max(max_lat.abs()).to_radians().cos();
+    let dlon = if cos > 0.0 { dlat / cos } else { 180.0 };
+    // a box crossing the antimeridian falls back to all longitudes
+    if point.lon - dlon < -180.0 || point.lon + dlon > 180.0 {
+        return (
+            LatLon {
+                lat: min_lat,
+                lon: -180.0,
+            },
+            LatLon {
+                lat: max_lat,
+                lon: 180.0,
+            },
+        );
+    }
+    (
+        LatLon {
+            lat: min_lat,
+            lon: point.lon - dlon,
+        },
+        LatLon {
+            lat: max_lat,
+            lon: point.lon + dlon,
+        },
+    )
+}
+
+/// Counts all cities or only the cities of one country, the code is matched case-insensitively.
+fn count_cities(conn: &mut PgConnection, country: Option<&str>) -> i64 {
+    let mut query = cities::table.count().into_boxed();
+    if let Some(country) = country {
+        query = query.filter(cities::country.ilike(country));
+    }
+    log_query(&query);
+    query.get_result(conn).expect("can't count cities")
+}
+
+/// Returns up to `limit` cities inside of the bounding box, ordered by id.
+fn cities_in_bbox(conn: &mut PgConnection, min: LatLon, max: LatLon, limit: i64) -> Vec<City> {
+    let query = cities::table
+        .select(City::as_select())
+        .filter(postgis_diesel::operators::intersects_2d(
+            cities::location,
+            st_make_envelope(min.lon, min.lat, max.lon, max.lat, 4326),
+        ))
+        .order_by(cities::id)
+        .limit(limit);
+    log_query(&query);
+    query.load(conn).expect("can't query cities in bbox")
+}
+
+/// Cities are matched by these, the first row wins if a key occurs more than once.
+type CityKey = (String, String, Option<String>);
+
+#[derive(Serialize)]
+struct DiffRow<'a> {
+    change: &'a str,
+    country: &'a str,
+    city: &'a str,
+    region: Option<&'a str>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    old_latitude: Option<f64>,
+    old_longitude: Option<f64>,
+}
+
+impl<'a> DiffRow<'a> {
+    fn new(change: &'a str, key: &'a CityKey, new: Option<LatLon>, old: Option<LatLon>) -> Self {
+        DiffRow {
+            change,
+            country: &key.0,
+            city: &key.1,
+            region: key.2.as_deref(),
+            latitude: new.map(|p| p.lat),
+            longitude: new.map(|p| p.lon),
+            old_latitude: old.map(|p| p.lat),
+            old_longitude: old.map(|p| p.lon),
+        }
+    }
+}
+
+/// Reads the whole input into memory, then streams the table past it.
+fn diff(c_pool: &Pool<ConnectionManager<PgConnection>>, args: &DiffArgs) {
+    let mut rdr = csv_reader(&args.csv);
+    let mut records = rdr.records().peekable();
+    let mapping = detect_columns(&mut records, &args.csv).unwrap_or_else(|e| {
+        eprintln!("can't map input columns: {}", e);
+        process::exit(1);
+    });
+    let mut input: HashMap<CityKey, LatLon> = HashMap::new();
+    let mut last_position = None;
+    for result in records {
+        let record = result.and_then(|row| {
+            last_position = row.position().cloned();
+            deserialize_city(
+                &row,
+                &mapping.fields,
+                &args.csv.null_tokens,
+                args.csv.empty_region_as,
+            )
+        });
+        let mut city = NewCity::from(record.unwrap_or_else(|e| {
+            panic!(
+                "can't parse city record: {}",
+                describe_csv_error(&e, last_position.as_ref())
+            )
+        }));
+        if args.csv.trim {
+            city.normalize_whitespace();
+        }
+        // without coordinates there is nothing to compare
+        if let Some(location) = &city.location {
+            input
+                .entry((city.country, city.city, city.region))
+                .or_insert_with(|| point_to_latlon(location));
+        }
+    }
+
+    let mut conn = c_pool.get().expect("can't get connection");
+    let query = cities::table
+        .select((
+            cities::country,
+            cities::city,
+            cities::region,
+            cities::location,
+        ))
+        .order_by(cities::id);
+    log_query(&query);
+    let mut wtr = csv::Writer::from_writer(io::stdout().lock());
+    let mut seen = HashSet::new();
+    let (mut removed, mut changed, mut unchanged) = (0, 0, 0);
+    for row in query
+        .load_iter::<(String, String, Option<String>, Point), DefaultLoadingMode>(&mut conn)
+        .expect("can't query cities")
+    {
+        let (country, city, region, location) = row.expect("can't read city");
+        let key = (country, city, region);
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+        let old = point_to_latlon(&location);
+        let change = match input.remove(&key) {
+            None => {
+                removed += 1;
+                DiffRow::new("removed", &key, None, Some(old))
+            }
+            Some(new)
+                if (new.lat - old.lat).abs() > args.tolerance
+                    || (new.lon - old.lon).abs() > args.tolerance =>
+            {
+                changed += 1;
+                DiffRow::new("moved", &key, Some(new), Some(old))
+            }
+            Some(_) => {
+                unchanged += 1;
+                continue;
+            }
+        };
+        wtr.serialize(change).expect("can't write diff");
+    }
+    let mut added: Vec<_> = input.into_iter().collect();
+    added.sort_by(|a, b| a.0.cmp(&b.0));
+    for (key, new) in &added {
+        wtr.serialize(DiffRow::new("added", key, Some(*new), None))
+            .expect("can't write diff");
+    }
+    wtr.flush().unwrap();
+    eprintln!(
+        "{} added, {} removed, {} moved, {} unchanged",
+        added.len(),
+        removed,
+        changed,
+        unchanged
+    );
+}
+
+/// Buffered writer to the file, or to stdout without a file.
+fn open_output(path: Option<&Path>) -> Box<dyn Write> {
+    match path {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).unwrap_or_else(|e| {
+                panic!("can't write {}: {}", path.display(), e)
+            })))
+        }
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    }
+}
+
+/// Reports the size of a finished output file, stdout has nothing to report.
+fn print_output_size(path: Option<&Path>) {
+    if let Some(path) = path {
+        let bytes = fs::metadata(path)
+            .unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e))
+            .len();
+        println!("wrote {} to {}", format_bytes(bytes as i64), path.display());
+    }
+}
+
+/// Destination of the rows of Export.
+enum ExportWriter {
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Ndjson(Box<dyn Write>),
+}
+
+impl ExportWriter {
+    fn write(&mut self, city: &City) {
+        match self {
+            ExportWriter::Csv(wtr) => wtr
+                .serialize(CityRow::from(city))
+                .expect("can't write city"),
+            ExportWriter::Ndjson(out) => {
+                let LatLon { lat, lon } = point_to_latlon(&city.location);
+                let mut object = geojson::city_properties(city);
+                object.insert("latitude".to_string(), serde_json::json!(lat));
+                object.insert("longitude".to_string(), serde_json::json!(lon));
+                serde_json::to_writer(&mut *out, &object).expect("can't write city");
+                out.write_all(b"\n").expect("can't write city");
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        match self {
+            ExportWriter::Csv(wtr) => wtr.flush(),
+            ExportWriter::Ndjson(out) => out.flush(),
+        }
+        .expect("can't write cities")
+    }
+}
+
+/// Writes the cities in chunks of `chunk_size` ordered by id, reporting the last id of
+/// each on stderr, or in one query when sorted by `locale`.
+#[allow(clippy::too_many_arguments)]
+fn export(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    format: ExportFormat,
+    delimiter: u8,
+    tsv: bool,
+    since: Option<DateTime<Utc>>,
+    locale: Option<&str>,
+    chunk_size: i64,
+    resume_from_id: Option<i64>,
+    output_file: Option<&Path>,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    // looked up before the output file is created, so a bad locale leaves nothing behind
+    let collation = locale.map(|locale| icu_collation(&mut conn, locale));
+    let out: Box<dyn Write> = match (output_file, resume_from_id) {
+        (Some(path), Some(_)) => Box::new(BufWriter::new(
+            OpenOptions::new()
+                .append(true)
+                .open(path)
+                .unwrap_or_else(|e| panic!("can't append to {}: {}", path.display(), e)),
+        )),
+        _ => open_output(output_file),
+    };
+    let mut wtr = match format {
+        ExportFormat::Csv => {
+            let mut builder = csv::WriterBuilder::new();
+            // the interrupted export wrote the header already
+            builder.has_headers(resume_from_id.is_none());
+            if tsv {
+                builder.delimiter(b'\t').quote_style(QuoteStyle::Never);
+            } else {
+                builder.delimiter(delimiter);
+            }
+            ExportWriter::Csv(Box::new(builder.from_writer(out)))
+        }
+        ExportFormat::Ndjson => ExportWriter::Ndjson(out),
+    };
+    let filtered = || {
+        let mut query = cities::table.select(City::as_select()).into_boxed();
+        if let Some(since) = since {
+            query = query.filter(cities::loaded_at.gt(since));
+        }
+        query
+    };
+    if let Some(collation) = collation {
+        // keyset pagination would need the collated name as the key, a single query is
+        // good enough for the alphabetical lists this is meant for
+        let query = filtered()
+            .order_by(diesel::dsl::sql::<Text>(&format!(
+                "accent_city COLLATE \"{}\"",
+                collation
+            )))
+            .then_order_by(cities::id);
+        log_query(&query);
+        for city in query
+            .load_iter::<City, DefaultLoadingMode>(&mut conn)
+            .expect("can't query cities")
+        {
+            wtr.write(&city.expect("can't read city"));
+        }
+    } else {
+        let mut exported = 0;
+        let mut last_id = resume_from_id;
+        loop {
+            let mut query = filtered().order_by(cities::id).limit(chunk_size);
+            if let Some(last_id) = last_id {
+                query = query.filter(cities::id.gt(last_id));
+            }
+            log_query(&query);
+            let chunk: Vec<City> = query.load(&mut conn).expect("can't query cities");
+            let Some(last) = chunk.last() else {
+                break;
+            };
+            last_id = Some(last.id);
+            for city in &chunk {
+                wtr.write(city);
+            }
+            // flushed before the id is reported, so a resumed export doesn't miss rows
+            wtr.flush();
+            exported += chunk.len();
+            eprintln!("exported {} cities, last id {}", exported, last.id);
+        }
+    }
+    wtr.flush();
+    drop(wtr);
+    print_output_size(output_file);
+}
+
+#[derive(QueryableByName)]
+struct CollationName {
+    #[diesel(sql_type = Text)]
+    collname: String,
+}
+
+/// Name of the ICU collation of the language usable in the current database, exits if
+/// there is none.
+fn icu_collation(conn: &mut PgConnection, locale: &str) -> String {
+    let found: Option<CollationName> = diesel::sql_query(
+        "SELECT collname FROM pg_collation \
+         WHERE collprovider = 'i' AND lower(collname) = lower($1 || '-x-icu') \
+         LIMIT 1",
+    )
+    .bind::<Text, _>(locale)
+    .get_result(conn)
+    .optional()
+    .expect("can't query collations");
+    // ICU collations are listed whatever the encoding, but some encodings like SQL_ASCII
+    // can't use them
+    let usable = found.filter(|c| {
+        diesel::sql_query(format!("SELECT '' COLLATE \"{}\"", c.collname))
+            .execute(conn)
+            .is_ok()
+    });
+    match usable {
+        Some(c) => c.collname,
+        None => {
+            eprintln!(
+                "no ICU collation for locale {} in this database, the server needs ICU \
+                 support and the database an encoding like UTF8",
+                locale
+            );
+            process::exit(1);
+        }
+    }
+}
+
+#[derive(QueryableByName)]
+struct Count {
+    #[diesel(sql_type = Int8)]
+    count: i64,
+}
+
+fn prune_bbox(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    min: LatLon,
+    max: LatLon,
+    invert: bool,
+    dry_run: bool,
+    geometry_column: &str,
+) {
+    if min.lat > max.lat || min.lon > max.lon {
+        eprintln!("min coordinates must not be greater than max coordinates");
+        process::exit(2);
+    }
+    let mut conn = c_pool.get().expect("can't get connection");
+    // for points the bounding box operator is an exact containment check and uses the GiST index
+    let inside = format!(
+        "\"{}\" && ST_MakeEnvelope($1, $2, $3, $4, 4326)",
+        geometry_column
+    );
+    let predicate = if invert {
+        inside
+    } else {
+        format!("NOT ({})", inside)
+    };
+    let sql = if dry_run {
+        format!("SELECT count(*) AS count FROM cities WHERE {}", predicate)
+    } else {
+        format!("DELETE FROM cities WHERE {}", predicate)
+    };
+    let query = diesel::sql_query(sql)
+        .bind::<Float8, _>(min.lon)
+        .bind::<Float8, _>(min.lat)
+        .bind::<Float8, _>(max.lon)
+        .bind::<Float8, _>(max.lat);
+    log_query(&query);
+    if dry_run {
+        let count: Count = query.get_result(&mut conn).expect("can't count cities");
+        println!("{} cities would be deleted", count.count);
+    } else {
+        let deleted = query.execute(&mut conn).expect("can't delete cities");
+        println!("deleted {} cities", deleted);
+    }
+}
+
+fn read_points(path: &Path) -> Vec<LatLon> {
+    let content = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("can't read points file {}: {}", path.display(), e));
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| l.parse().unwrap_or_else(|e| panic!("{}", e)))
+        .collect()
+}
+
+#[derive(QueryableByName)]
+struct PairDistance {
+    #[diesel(sql_type = Int8)]
+    i: i64,
+    #[diesel(sql_type = Int8)]
+    j: i64,
+    #[diesel(sql_type = Float8)]
+    distance: f64,
+}
+
+/// Points of --point followed by those of --file.
+fn matrix_points(points: &[LatLon], file: Option<&Path>) -> Vec<LatLon> {
+    let mut points = points.to_vec();
+    if let Some(file) = file {
+        points.extend(read_points(file));
+    }
+    points
+}
+
+fn db_distance_matrix(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    points: &[LatLon],
+) -> Vec<Vec<f64>> {
+    let mut conn = c_pool.get().expect("can't get connection");
+    // the matrix is symmetric with zero diagonal, so only pairs with i < j are computed
+    let query = diesel::sql_query(
+        "SELECT a.i, b.i AS j, \
+                ST_DistanceSphere(ST_MakePoint(a.lon, a.lat), ST_MakePoint(b.lon, b.lat)) AS distance \
+         FROM unnest($1::float8[], $2::float8[]) WITH ORDINALITY AS a(lat, lon, i) \
+         JOIN unnest($1::float8[], $2::float8[]) WITH ORDINALITY AS b(lat, lon, i) ON a.i < b.i",
+    )
+    .bind::<Array<Float8>, _>(points.iter().map(|p| p.lat).collect::<Vec<_>>())
+    .bind::<Array<Float8>, _>(points.iter().map(|p| p.lon).collect::<Vec<_>>());
+    log_query(&query);
+    let pairs: Vec<PairDistance> = query.load(&mut conn).expect("can't compute distances");
+    let mut matrix = vec![vec![0.0; points.len()]; points.len()];
+    for p in pairs {
+        // ordinality is 1-based
+        let (i, j) = (p.i as usize - 1, p.j as usize - 1);
+        matrix[i][j] = p.distance;
+        matrix[j][i] = p.distance;
+    }
+    matrix
+}
+
+fn client_distance_matrix(points: &[LatLon], distance: fn(LatLon, LatLon) -> f64) -> Vec<Vec<f64>> {
+    let mut matrix = vec![vec![0.0; points.len()]; points.len()];
+    for i in 0..points.len() {
+        for j in i + 1..points.len() {
+            let d = distance(points[i], points[j]);
+            matrix[i][j] = d;
+            matrix[j][i] = d;
+        }
+    }
+    matrix
+}
+
+fn print_distance_matrix(points: &[LatLon], matrix: Vec<Vec<f64>>) {
+    let mut wtr = csv::Writer::from_writer(io::stdout());
+    let labels: Vec<String> = points
+        .iter()
+        .map(|p| format!("{} {}", p.lat, p.lon))
+        .collect();
+    wtr.write_field("").unwrap();
+    wtr.write_record(&labels).unwrap();
+    for (label, row) in labels.iter().zip(matrix) {
+        wtr.write_field(label).unwrap();
+        wtr.write_record(row.iter().map(|d| format!("{:.1}", d)))
+            .unwrap();
+    }
+    wtr.flush().unwrap();
+}
+
+#[derive(QueryableByName)]
+struct ClosestPair {
+    #[diesel(sql_type = Int8)]
+    a_id: i64,
+    #[diesel(sql_type = Text)]
+    a_name: String,
+    #[diesel(sql_type = Text)]
+    a_country: String,
+    #[diesel(sql_type = Float8)]
+    a_lat: f64,
+    #[diesel(sql_type = Float8)]
+    a_lon: f64,
+    #[diesel(sql_type = Int8)]
+    b_id: i64,
+    #[diesel(sql_type = Text)]
+    b_name: String,
+    #[diesel(sql_type = Text)]
+    b_country: String,
+    #[diesel(sql_type = Float8)]
+    b_lat: f64,
+    #[diesel(sql_type = Float8)]
+    b_lon: f64,
+    #[diesel(sql_type = Float8)]
+    distance: f64,
+}
+
+/// Prints the `top` closest pairs among the nearest neighbor of every city.
+///
+/// Every city costs one KNN probe of the GiST index, so the whole table is O(n log n) and
+/// takes minutes for the full GeoNames set; --country keeps it to the cities of one
+/// country. Only the nearest neighbor of each city is looked at, so a city with two close
+/// neighbors shows up with the closer one only. The neighbor is nearest by the planar `<->`
+/// distance, which differs from the printed sphere distance far away from the equator.
+fn closest_pairs(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    top: i64,
+    country: Option<&str>,
+    units: Units,
+    geometry_column: &str,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    // mutual nearest neighbors are found from both sides, DISTINCT ON keeps one of them
+    let query = diesel::sql_query(format!(
+        "SELECT * FROM ( \
+             SELECT DISTINCT ON (least(a.id, b.id), greatest(a.id, b.id)) \
+                    a.id AS a_id, COALESCE(NULLIF(a.accent_city, ''), a.city) AS a_name, \
+                    a.country AS a_country, ST_Y(a.\"{col}\") AS a_lat, ST_X(a.\"{col}\") AS a_lon, \
+                    b.id AS b_id, b.name AS b_name, b.country AS b_country, \
+                    ST_Y(b.location) AS b_lat, ST_X(b.location) AS b_lon, \
+                    ST_DistanceSphere(a.\"{col}\", b.location) AS distance \
+             FROM cities a \
+             CROSS JOIN LATERAL ( \
+                 SELECT id, COALESCE(NULLIF(accent_city, ''), city) AS name, country, \
+                        \"{col}\" AS location \
+                 FROM cities \
+                 WHERE id <> a.id AND ($1::text IS NULL OR country ILIKE $1) \
+                 ORDER BY \"{col}\" <-> a.\"{col}\" \
+                 LIMIT 1 \
+             ) b \
+             WHERE $1::text IS NULL OR a.country ILIKE $1 \
+         ) pairs \
+         ORDER BY distance, a_id \
+         LIMIT $2",
+        col = geometry_column
+    ))
+    .bind::<Nullable<Text>, _>(country)
+    .bind::<Int8, _>(top);
+    log_query(&query);
+    let pairs: Vec<ClosestPair> = query.load(&mut conn).expect("can't query closest pairs");
+    for p in pairs {
+        println!(
+            "{}\t{} #{}, {} ({:.4}, {:.4})\t{} #{}, {} ({:.4}, {:.4})",
+            format_distance(p.distance, units),
+            p.a_name,
+            p.a_id,
+            p.a_country,
+            p.a_lat,
+            p.a_lon,
+            p.b_name,
+            p.b_id,
+            p.b_country,
+            p.b_lat,
+            p.b_lon
+        );
+    }
+}
+
+fn bench_get(c_pool: &Pool<ConnectionManager<PgConnection>>, args: &BenchArgs) {
+    let mut rng = thread_rng();
+    let points: Vec<LatLon> = (0..500)
+        .map(|_| LatLon {
+            lat: rng.gen_range(-90.0..90.0),
+            lon: rng.gen_range(-180.0..180.0),
+        })
+        .collect();
+    let mut conn = c_pool.get().unwrap();
+    let start = SystemTime::now();
+    let deadline = args
+        .max_duration
+        .map(|secs| start + Duration::from_secs(secs));
+    let slow_threshold = args.slow_threshold_ms.map(Duration::from_millis);
+    let indexed = bench_queries(&mut conn, &points, slow_threshold, deadline);
+    println!("elapsed {:?}", start.elapsed());
+    if !args.compare {
+        if deadline.is_some() {
+            diesel::sql_query("RESET statement_timeout")
+                .execute(&mut conn)
+                .expect("can't reset statement timeout");
+        }
+        print_percentiles("index", &indexed);
+        return;
+    }
+    // session level settings which make the planner fall back to a sequential scan
+    diesel::sql_query("SET enable_indexscan = off")
+        .execute(&mut conn)
+        .expect("can't disable index scan");
+    diesel::sql_query("SET enable_bitmapscan = off")
+        .execute(&mut conn)
+        .expect("can't disable bitmap scan");
+    let start = SystemTime::now();
+    // without the index every query is slow, so only the indexed run reports them
+    let sequential = bench_queries(&mut conn, &points, None, deadline);
+    println!("elapsed without index {:?}", start.elapsed());
+    diesel::sql_query("RESET ALL")
+        .execute(&mut conn)
+        .expect("can't reset session settings");
+    print_percentiles("index", &indexed);
+    print_percentiles("seqscan", &sequential);
+}
+
+/// Runs the nearest neighbors query for every point and returns per query durations.
+/// With a deadline every query gets the remaining time as statement timeout, so a hanging
+/// query is cancelled there; it isn't counted and the remaining points are skipped.
+fn bench_queries(
+    conn: &mut PgConnection,
+    points: &[LatLon],
+    slow_threshold: Option<Duration>,
+    deadline: Option<SystemTime>,
+) -> Vec<Duration> {
+    let mut durations = Vec::with_capacity(points.len());
+    for p in points {
+        if let Some(deadline) = deadline {
+            let remaining = deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            if remaining.is_zero() {
+                println!(
+                    "stopped at --max-duration after {} of {} queries",
+                    durations.len(),
+                    points.len()
+                );
+                break;
+            }
+            // not timed; at least 1 ms, a statement_timeout of 0 disables the timeout
+            diesel::sql_query(format!(
+                "SET statement_timeout = {}",
+                remaining.as_millis().max(1)
+            ))
+            .execute(conn)
+            .expect("can't set statement timeout");
+        }
+        let query = cities::table
+            .order_by(postgis_diesel::operators::distance_2d(
+                cities::location,
+                Point::new(p.lon, p.lat, Some(4326)),
+            ))
+            .then_order_by(cities::id.asc())
+            .limit(500);
+        log_query(&query);
+        let start = SystemTime::now();
+        match query.execute(conn) {
+            Ok(_) => {}
+            // cancelled by the statement timeout, the next iteration stops
+            Err(_) if deadline.is_some_and(|deadline| SystemTime::now() >= deadline) => continue,
+            Err(e) => panic!("nothing found: {}", e),
+        }
+        let elapsed = start.elapsed().unwrap();
+        if slow_threshold.is_some_and(|threshold| elapsed > threshold) {
+            println!("slow query {:?} at {},{}", elapsed, p.lat, p.lon);
+        }
+        durations.push(elapsed);
+    }
+    durations
+}
+
+/// Loads the first `--rows` cities of the input once per batch size. The temporary table
+/// shadows cities for this connection only; it has the same indexes but writes no WAL, so
+/// the throughput is higher than that of a real load, the ratios between sizes still hold.
+fn tune(c_pool: &Pool<ConnectionManager<PgConnection>>, args: &TuneArgs) {
+    let mut rdr = csv_reader(&args.csv);
+    let mut records = rdr.records().peekable();
+    let mapping = detect_columns(&mut records, &args.csv).unwrap_or_else(|e| {
+        eprintln!("can't map input columns: {}", e);
+        process::exit(1);
+    });
+    let mut last_position = None;
+    let cities: Vec<NewCity> = records
+        .map(|result| {
+            let record = result.and_then(|row| {
+                last_position = row.position().cloned();
+                deserialize_city(
+                    &row,
+                    &mapping.fields,
+                    &args.csv.null_tokens,
+                    args.csv.empty_region_as,
+                )
+            });
+            NewCity::from(record.unwrap_or_else(|e| {
+                panic!(
+                    "can't parse city record: {}",
+                    describe_csv_error(&e, last_position.as_ref())
+                )
+            }))
+        })
+        // the temporary table can't take rows without coordinates either
+        .filter(|city| city.location.is_some())
+        .take(args.rows)
+        .collect();
+    if cities.is_empty() {
+        eprintln!("no rows with coordinates in {}", args.csv.input.display());
+        process::exit(1);
+    }
+
+    let mut conn = c_pool.get().expect("can't get connection");
+    run_migration(&mut conn);
+    // without the defaults, which would draw ids from the sequence of the real table
+    diesel::sql_query(
+        "CREATE TEMPORARY TABLE cities (LIKE cities INCLUDING ALL EXCLUDING DEFAULTS)",
+    )
+    .execute(&mut conn)
+    .expect("can't create temporary cities table");
+    diesel::sql_query(
+        "ALTER TABLE pg_temp.cities \
+             ALTER COLUMN id ADD GENERATED BY DEFAULT AS IDENTITY, \
+             ALTER COLUMN loaded_at SET DEFAULT now()",
+    )
+    .execute(&mut conn)
+    .expect("can't create temporary cities table");
+    println!("{:>10}{:>14}{:>12}", "batch", "elapsed", "rows/s");
+    for &batch_size in &args.batch_sizes {
+        diesel::sql_query("TRUNCATE pg_temp.cities")
+            .execute(&mut conn)
+            .expect("can't truncate temporary cities table");
+        let start = SystemTime::now();
+        for batch in cities.chunks(batch_size as usize) {
+            insert_batch(
+                &mut conn,
+                batch,
+                args.insert_mode,
+                DEFAULT_GEOMETRY_COLUMN,
+                4326,
+            );
+        }
+        let elapsed = start.elapsed().unwrap();
+        println!(
+            "{:>10}{:>14}{:>12.0}",
+            batch_size,
+            format!("{:.2?}", elapsed),
+            cities.len() as f64 / elapsed.as_secs_f64()
+        );
+    }
+    println!("loaded {} cities per batch size", cities.len());
+}
+
+fn random_city(rng: &mut StdRng, i: usize) -> NewCity {
+    const COUNTRIES: [&str; 5] = ["us", "de", "fr", "br", "in"];
+    NewCity {
+        country: COUNTRIES[rng.gen_range(0..COUNTRIES.len())].to_string(),
+        city: format!("bench city {}", i),
+        accent_city: format!("Bench City {}", i),
+        region: Some(format!("{:02}", rng.gen_range(0..100))),
+        location: Some(Point::new(
+            rng.gen_range(-180.0..180.0),
+            rng.gen_range(-90.0..90.0),
+            Some(4326),
+        )),
+        population: rng.gen_bool(0.3).then(|| rng.gen_range(100..10_000_000)),
+        raw: None,
+        display_name: None,
+        srid: Some(4326),
+        latitude: None,
+        longitude: None,
+    }
+}
+
+/// Name made of two or three random syllables, e.g. "Moraven".
+fn synthetic_name(rng: &mut StdRng) -> String {
+    const SYLLABLES: [&str; 24] = [
+        "ka", "mo", "ra", "ven", "li", "tor", "sa", "bel", "ne", "dor", "fi", "gan", "hu", "ro",
+        "ta", "mir", "lo", "ber", "cas", "nu", "pe", "stad", "vi", "burg",
+    ];
+    let count = rng.gen_range(2..=3);
+    let name: String = (0..count)
+        .map(|_| SYLLABLES[rng.gen_range(0..SYLLABLES.len())])
+        .collect();
+    let mut chars = name.chars();
+    let first = chars.next().unwrap().to_ascii_uppercase();
+    first.to_string() + chars.as_str()
+}
+
+fn generate(count: usize, output: &Path, seed: u64) {
+    const COUNTRIES: [&str; 12] = [
+        "us", "de", "fr", "br", "in", "cn", "ru", "mx", "ng", "id", "it", "au",
+    ];
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut wtr = csv::Writer::from_path(output)
+        .unwrap_or_else(|e| panic!("can't write {}: {}", output.display(), e));
+    for _ in 0..count {
+        let accent_city = synthetic_name(&mut rng);
+        let region = format!("{:02}", rng.gen_range(1..100));
+        wtr.serialize(CityRow {
+            country: COUNTRIES[rng.gen_range(0..COUNTRIES.len())],
+            city: &accent_city.to_lowercase(),
+            accent_city: &accent_city,
+            region: Some(&region),
+            // where cities are, rather than near the poles
+            latitude: rng.gen_range(-56.0..71.0),
+            longitude: rng.gen_range(-180.0..180.0),
+            // like in worldcitiespop, most small places have no population
+            population: rng
+                .gen_bool(0.1)
+                .then(|| 10f64.powf(rng.gen_range(2.0..7.0)) as i64),
+        })
+        .expect("can't write city");
+    }
+    wtr.flush().expect("can't write city");
+    println!("wrote {} cities to {}", count, output.display());
+}
+
+fn bench_insert(c_pool: &Pool<ConnectionManager<PgConnection>>, args: &BenchArgs) {
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let cities: Vec<NewCity> = (0..args.rows).map(|i| random_city(&mut rng, i)).collect();
+    let mut conn = c_pool.get().expect("can't get connection");
+    let mut latencies = Vec::new();
+    let start = SystemTime::now();
+    let result = conn.transaction::<(), _, _>(|conn| {
+        for batch in cities.chunks(args.batch_size as usize) {
+            let batch_start = SystemTime::now();
+            insert_batch(conn, batch, args.insert_mode, DEFAULT_GEOMETRY_COLUMN, 4326);
+            latencies.push(batch_start.elapsed().unwrap());
+        }
+        // keep the table as it was
+        Err(diesel::result::Error::RollbackTransaction)
+    });
+    match result {
+        Err(diesel::result::Error::RollbackTransaction) => {}
+        other => other.expect("can't roll back benchmark inserts"),
+    }
+    let elapsed = start.elapsed().unwrap();
+    println!(
+        "inserted {} cities in {:?}, {:.0} rows/s",
+        args.rows,
+        elapsed,
+        args.rows as f64 / elapsed.as_secs_f64()
+    );
+    if !latencies.is_empty() {
+        print_percentiles("batch", &latencies);
+    }
+}
+
+fn print_percentiles(label: &str, durations: &[Duration]) {
+    if durations.is_empty() {
+        println!("{:<8} no queries finished", label);
+        return;
+    }
+    let mut sorted = durations.to_vec();
+    sorted.sort();
+    let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+    println!(
+        "{:<8} p50 {:>10.2?} p90 {:>10.2?} p99 {:>10.2?} max {:>10.2?}",
+        label,
+        percentile(0.5),
+        percentile(0.9),
+        percentile(0.99),
+        sorted[sorted.len() - 1]
+    );
+}
+
+fn major_cities(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    min_population: i64,
+    refresh: bool,
+    geometry_column: &str,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    if refresh {
+        diesel::sql_query("REFRESH MATERIALIZED VIEW major_cities")
+            .execute(&mut conn)
+            .expect("can't refresh major_cities view");
+    } else {
+        // an existing view may have another threshold, which it would keep
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::sql_query("DROP MATERIALIZED VIEW IF EXISTS major_cities").execute(conn)?;
+            // DDL doesn't accept bind parameters, so the threshold is inlined
+            diesel::sql_query(format!(
+                "CREATE MATERIALIZED VIEW major_cities AS \
+                 SELECT * FROM cities WHERE population >= {}",
+                min_population
+            ))
+            .execute(conn)?;
+            diesel::sql_query(format!(
+                "CREATE INDEX major_cities_location_idx ON major_cities USING GIST (\"{}\")",
+                geometry_column
+            ))
+            .execute(conn)?;
+            Ok(())
+        })
+        .expect("can't create major_cities view");
+    }
+    let count: i64 = major_cities::table
+        .count()
+        .get_result(&mut conn)
+        .expect("can't count major cities");
+    println!("major_cities contains {} cities", count);
+}
+
+/// Grid cells per tile width of a level of detail table, so a map tile shows at most
+/// the square of this many cities.
+const LOD_CELLS_PER_TILE: f64 = 4.0;
+
+/// Builds one table per zoom level with the most populous city, the lowest id among
+/// equals, of every grid cell. A tile at zoom z is 360 / 2^z degrees wide and its cells
+/// a `LOD_CELLS_PER_TILE`th of that.
+///
+/// A table holds at most one city per occupied cell. Low zoom levels are tiny, but from
+/// around zoom 10 cells are smaller than most towns and the table approaches a copy of
+/// cities including its index, so the printed sizes are worth checking.
+fn build_lod(c_pool: &Pool<ConnectionManager<PgConnection>>, levels: &[u8], geometry_column: &str) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    for &zoom in levels {
+        let table = format!("cities_lod_{}", zoom);
+        let cell = 360.0 / 2f64.powi(zoom as i32) / LOD_CELLS_PER_TILE;
+        conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::sql_query(format!("DROP TABLE IF EXISTS {}", table)).execute(conn)?;
+            // DDL doesn't accept bind parameters, so the cell size is inlined
+            diesel::sql_query(format!(
+                "CREATE TABLE {table} AS \
+                 SELECT DISTINCT ON (ST_SnapToGrid(\"{col}\", {cell:?})) \
+                        id, country, city, accent_city, region, \"{col}\" AS location, population \
+                 FROM cities \
+                 WHERE \"{col}\" IS NOT NULL \
+                 ORDER BY ST_SnapToGrid(\"{col}\", {cell:?}), population DESC NULLS LAST, id",
+                table = table,
+                col = geometry_column,
+                cell = cell
+            ))
+            .execute(conn)?;
+            diesel::sql_query(format!(
+                "CREATE INDEX {table}_location_idx ON {table} USING GIST (location)",
+                table = table
+            ))
+            .execute(conn)?;
+            Ok(())
+        })
+        .unwrap_or_else(|e| panic!("can't build {}: {}", table, e));
+        let size = diesel::sql_query("SELECT pg_total_relation_size($1::regclass) AS size")
+            .bind::<Text, _>(&table)
+            .get_result::<Size>(&mut conn)
+            .expect("can't get table size")
+            .size;
+        let count = diesel::sql_query(format!("SELECT count(*) AS size FROM {}", table))
+            .get_result::<Size>(&mut conn)
+            .expect("can't count cities")
+            .size;
+        println!(
+            "{}: {} cities in cells of {:.4}°, {} with index",
+            table,
+            count,
+            cell,
+            format_bytes(size)
+        );
+    }
+}
+
+#[derive(QueryableByName)]
+struct SequenceUsage {
+    #[diesel(sql_type = Nullable<Int8>)]
+    last_value: Option<i64>,
+    #[diesel(sql_type = Int8)]
+    max_value: i64,
+}
+
+/// Warns when the id sequence of cities has used up 90% of its range, which can only
+/// happen while it's still 32 bit, e.g. with the bigint migration reverted.
+fn warn_if_ids_run_out(conn: &mut PgConnection) {
+    let usage = diesel::sql_query(
+        "SELECT last_value, max_value FROM pg_sequences \
+         WHERE format('%I.%I', schemaname, sequencename)::regclass \
+               = pg_get_serial_sequence('cities', 'id')::regclass",
+    )
+    .get_result::<SequenceUsage>(conn)
+    .optional()
+    .expect("can't query id sequence");
+    if let Some(SequenceUsage {
+        last_value: Some(last_value),
+        max_value,
+    }) = usage
+    {
+        if last_value as f64 > max_value as f64 * 0.9 {
+            println!(
+                "warning: the cities id sequence is at {} of at most {}, \
+                 a large load may run out of ids",
+                last_value, max_value
+            );
+        }
+    }
+}
+
+/// Replaces the centroids of all regions. Cities without region form one group per
+/// country. The centroid is planar in degrees, so a region spanning the antimeridian
+/// gets one on the other side of the globe.
+fn build_region_centroids(c_pool: &Pool<ConnectionManager<PgConnection>>, geometry_column: &str) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    run_migration(&mut conn);
+    let regions = conn
+        .transaction::<_, diesel::result::Error, _>(|conn| {
+            diesel::sql_query("DELETE FROM region_centroids").execute(conn)?;
+            diesel::sql_query(format!(
+                "INSERT INTO region_centroids (country, region, cities, centroid) \
+                 SELECT country, region, count(*), ST_Centroid(ST_Collect(\"{col}\")) \
+                 FROM cities \
+                 WHERE \"{col}\" IS NOT NULL \
+                 GROUP BY country, region",
+                col = geometry_column
+            ))
+            .execute(conn)
+        })
+        .expect("can't build region centroids");
+    println!("computed centroids of {} regions", regions);
+}
+
+#[derive(QueryableByName)]
+struct Srid {
+    #[diesel(sql_type = Int4)]
+    srid: i32,
+}
+
+/// Exits if any city has a geometry with another SRID than the 4326 of the query points,
+/// which makes distance functions fail or compare coordinates of different systems.
+fn check_srid(c_pool: &Pool<ConnectionManager<PgConnection>>, geometry_column: &str) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    let query = diesel::sql_query(format!(
+        "SELECT DISTINCT ST_SRID(\"{col}\") AS srid FROM cities \
+         WHERE \"{col}\" IS NOT NULL ORDER BY srid",
+        col = geometry_column
+    ));
+    log_query(&query);
+    let srids: Vec<Srid> = query.load(&mut conn).expect("can't query SRIDs");
+    let other: Vec<String> = srids
+        .iter()
+        .filter(|s| s.srid != 4326)
+        .map(|s| s.srid.to_string())
+        .collect();
+    if !other.is_empty() {
+        eprintln!(
+            "cities have geometries with SRID {}, queries expect 4326 (see --strict-srid)",
+            other.join(", ")
+        );
+        process::exit(1);
+    }
+}
+
+/// Whether a unique index, which includes those of unique constraints, covers exactly
+/// `columns` in any order. ON CONFLICT needs one to infer the conflict target.
+fn has_unique_index(conn: &mut PgConnection, columns: &[String]) -> bool {
+    let query = diesel::sql_query(
+        "SELECT EXISTS ( \
+             SELECT 1 FROM pg_index i \
+             WHERE i.indrelid = 'cities'::regclass AND i.indisunique AND i.indpred IS NULL \
+               AND i.indnkeyatts = cardinality($1::text[]) \
+               AND (SELECT array_agg(a.attname::text ORDER BY a.attname) \
+                    FROM pg_attribute a \
+                    WHERE a.attrelid = i.indrelid \
+                      AND a.attnum = ANY ((i.indkey::int2[])[0:i.indnkeyatts - 1])) \
+                 = (SELECT array_agg(c ORDER BY c) FROM unnest($1::text[]) c) \
+         ) AS exists",
+    )
+    .bind::<Array<Text>, _>(columns);
+    log_query(&query);
+    let exists: Exists = query
+        .get_result(conn)
+        .expect("can't query indexes of cities");
+    exists.exists
+}
+
+fn srid_exists(conn: &mut PgConnection, srid: u32) -> bool {
+    let query = diesel::sql_query(
+        "SELECT EXISTS (SELECT 1 FROM spatial_ref_sys WHERE srid = $1) AS exists",
+    )
+    .bind::<Int4, _>(srid as i32);
+    log_query(&query);
+    let exists: Exists = query.get_result(conn).expect("can't query spatial_ref_sys");
+    exists.exists
+}
+
+/// Whether `column` of the cities table takes NULL; the migrations create the geometry
+/// column NOT NULL.
+fn is_nullable(conn: &mut PgConnection, column: &str) -> bool {
+    let query = diesel::sql_query(
+        "SELECT EXISTS (SELECT 1 FROM pg_attribute \
+                        WHERE attrelid = 'cities'::regclass AND attname = $1 \
+                        AND NOT attnotnull) AS exists",
+    )
+    .bind::<Text, _>(column);
+    log_query(&query);
+    let exists: Exists = query
+        .get_result(conn)
+        .expect("can't look up cities columns");
+    exists.exists
+}
+
+#[derive(QueryableByName)]
+struct IdRange {
+    #[diesel(sql_type = Nullable<Int8>)]
+    min_id: Option<i64>,
+    #[diesel(sql_type = Nullable<Int8>)]
+    max_id: Option<i64>,
+}
+
+#[derive(QueryableByName)]
+struct BatchUpdate {
+    #[diesel(sql_type = Int8)]
+    count: i64,
+    #[diesel(sql_type = Nullable<Int8>)]
+    max_id: Option<i64>,
+}
+
+/// Sets `column` to `expression` for cities where it's NULL, or for all cities with
+/// `all`, in batches of `batch_size` cities ordered by id, each continuing after the last
+/// id of the previous one, and prints the progress after each.
+fn backfill(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    column: BackfillColumn,
+    expression: String,
+    all: bool,
+    batch_size: i64,
+    resume_from_id: Option<i64>,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    run_migration(&mut conn);
+    let query = diesel::sql_query("SELECT min(id) AS min_id, max(id) AS max_id FROM cities");
+    log_query(&query);
+    let range: IdRange = query.get_result(&mut conn).expect("can't query city ids");
+    let (Some(min_id), Some(max_id)) = (range.min_id, range.max_id) else {
+        println!("no cities");
+        return;
+    };
+    // without --all the batches only hold cities which still need the column, so ids
+    // of cities done already aren't walked again
+    let sql = format!(
+        "WITH batch AS (\
+           SELECT id FROM cities WHERE id > $1{filter} ORDER BY id LIMIT $2\
+         ), updated AS (\
+           UPDATE cities SET {col} = {expr} FROM batch \
+           WHERE cities.id = batch.id RETURNING cities.id\
+         ) \
+         SELECT count(*) AS count, max(id) AS max_id FROM updated",
+        col = column.name(),
+        expr = expression,
+        filter = if all {
+            String::new()
+        } else {
+            format!(" AND {} IS NULL", column.name())
+        }
+    );
+    let mut updated = 0;
+    let mut last_id = resume_from_id.unwrap_or(min_id - 1);
+    loop {
+        let query = diesel::sql_query(&sql)
+            .bind::<Int8, _>(last_id)
+            .bind::<Int8, _>(batch_size);
+        log_query(&query);
+        let batch: BatchUpdate = query
+            .get_result(&mut conn)
+            .unwrap_or_else(|e| panic!("can't compute {}: {}", column.name(), e));
+        let Some(max_batch_id) = batch.max_id else {
+            break;
+        };
+        updated += batch.count;
+        last_id = max_batch_id;
+        let done = (last_id.min(max_id) + 1 - min_id).max(0) as f64 / (max_id + 1 - min_id) as f64;
+        println!(
+            "computed {} of {} cities, last id {}, {:.1}% of the ids",
+            column.name(),
+            updated,
+            last_id,
+            done * 100.0
+        );
+    }
+}
+
+/// Sets the rank of cities which have none.
+fn backfill_rank(conn: &mut PgConnection, expression: &str) -> usize {
+    let query = diesel::sql_query(format!(
+        "UPDATE cities SET rank = {} WHERE rank IS NULL",
+        expression
+    ));
+    log_query(&query);
+    query
+        .execute(conn)
+        .unwrap_or_else(|e| panic!("can't compute rank with \"{}\": {}", expression, e))
+}
+
+/// Sets the geohash of cities which have none, or of all cities with `all`.
+fn backfill_geohash(
+    conn: &mut PgConnection,
+    precision: i32,
+    all: bool,
+    geometry_column: &str,
+) -> usize {
+    let query = diesel::sql_query(format!(
+        "UPDATE cities SET geohash = ST_GeoHash(\"{}\", $1){}",
+        geometry_column,
+        if all { "" } else { " WHERE geohash IS NULL" }
+    ))
+    .bind::<Int4, _>(precision);
+    log_query(&query);
+    query.execute(conn).expect("can't compute geohash")
+}
+
+/// Where Upload sends the cities.
+enum UploadTarget<'a> {
+    Database(&'a Pool<ConnectionManager<PgConnection>>),
+    /// SQL script of the inserts, see `--sql-out`.
+    SqlFile(&'a Path),
+}
+
+/// Connection or script the batches of an Upload go to.
+enum Sink {
+    Database(PooledConnection<ConnectionManager<PgConnection>>),
+    Sql(BufWriter<File>),
+}
+
+impl Sink {
+    /// Runs a statement which returns nothing, or appends it to the script.
+    fn execute(&mut self, sql: &str) {
+        match self {
+            Sink::Database(conn) => {
+                let query = diesel::sql_query(sql);
+                log_query(&query);
+                query
+                    .execute(conn)
+                    .unwrap_or_else(|e| panic!("can't run {}: {}", sql, e));
+            }
+            Sink::Sql(out) => writeln!(out, "{};", sql).expect("can't write SQL script"),
+        }
+    }
+}
+
+/// Drops and builds the index of the geometry column around the batches of an Upload,
+/// see `--index-strategy`.
+struct IndexSchedule {
+    strategy: IndexStrategy,
+    every: usize,
+    index: String,
+    geometry_column: String,
+    /// Batches inserted since the index was dropped, `None` while it exists.
+    unindexed_batches: Option<usize>,
+}
+
+impl IndexSchedule {
+    fn new(args: &UploadArgs, geometry_column: &str) -> Self {
+        IndexSchedule {
+            strategy: args.index_strategy,
+            every: args.index_every as usize,
+            index: location_index(geometry_column),
+            geometry_column: geometry_column.to_string(),
+            unindexed_batches: None,
+        }
+    }
+
+    fn before_batch(&mut self, sink: &mut Sink) {
+        if self.strategy != IndexStrategy::During && self.unindexed_batches.is_none() {
+            sink.execute(&format!("DROP INDEX IF EXISTS {}", self.index));
+            self.unindexed_batches = Some(0);
+        }
+    }
+
+    fn after_batch(&mut self, sink: &mut Sink, timings: &mut Timings) {
+        if let Some(batches) = &mut self.unindexed_batches {
+            *batches += 1;
+            if self.strategy == IndexStrategy::Periodic && *batches == self.every {
+                self.finish(sink, timings);
+            }
+        }
+    }
+
+    /// Builds the index if it was dropped.
+    fn finish(&mut self, sink: &mut Sink, timings: &mut Timings) {
+        if self.unindexed_batches.take().is_none() {
+            return;
+        }
+        println!("building {}", self.index);
+        let started = Instant::now();
+        sink.execute(&format!(
+            "CREATE INDEX IF NOT EXISTS {} ON cities USING GIST (\"{}\")",
+            self.index, self.geometry_column
+        ));
+        timings.span("build index", started, serde_json::Value::Null);
+    }
+}
+
+fn insert_data(target: UploadTarget, args: &UploadArgs, geometry_column: &str) {
+    let start = SystemTime::now();
+    let mut timings = Timings {
+        profile: args.profile.as_ref().map(|_| profile::Profile::new()),
+        ..Timings::default()
+    };
+    let upload_started = Instant::now();
+    let pipe = args.csv.is_pipe();
+    if pipe {
+        let needs_hash = [
+            (args.skip_unchanged, "--skip-unchanged"),
+            (args.expected_sha256.is_some(), "--expected-sha256"),
+            (args.checkpoint_file.is_some(), "--checkpoint-file"),
+        ];
+        if let Some((_, option)) = needs_hash.iter().find(|(given, _)| *given) {
+            eprintln!(
+                "{} needs the hash of the input, which a pipe can't be read twice for",
+                option
+            );
+            process::exit(2);
+        }
+    }
+    // a pipe would be drained by hashing it, its load run is recorded without a hash
+    let sha256 = if pipe {
+        String::new()
+    } else {
+        load_runs::file_sha256(&args.csv.input)
+    };
+    timings.span("hash input", upload_started, serde_json::Value::Null);
+    if let Some(expected) = &args.expected_sha256 {
+        if *expected != sha256 {
+            eprintln!(
+                "SHA-256 of {} is {}, expected {}; the file is corrupted or not the expected one",
+                args.csv.input.display(),
+                sha256,
+                expected
+            );
+            process::exit(1);
+        }
+    }
+    let mut sink = match target {
+        UploadTarget::Database(c_pool) => {
+            Sink::Database(c_pool.get().expect("can't get connection"))
+        }
+        UploadTarget::SqlFile(path) => Sink::Sql(create_sql_script(path)),
+    };
+    let prepare_started = Instant::now();
+    let mut country_boxes = None;
+    if let Sink::Database(conn) = &mut sink {
+        run_migration(conn);
+        if args.skip_unchanged {
+            if let Some(run) = load_runs::find_succeeded(conn, &sha256) {
+                println!(
+                    "{} was already loaded by run {} at {}, skipping",
+                    args.csv.input.display(),
+                    run.id,
+                    run.started_at
+                );
+                return;
+            }
+        }
+        warn_if_ids_run_out(conn);
+        let columns = new_city_columns(geometry_column);
+        if let Some(column) = args
+            .conflict_on
+            .iter()
+            .find(|c| !columns.contains(&c.as_str()))
+        {
+            // the written rows are paired with the input on them
+            eprintln!(
+                "--conflict-on column {} isn't one Upload writes, use some of {}",
+                column,
+                columns.join(", ")
+            );
+            process::exit(2);
+        }
+        if !args.conflict_on.is_empty() && !has_unique_index(conn, &args.conflict_on) {
+            eprintln!(
+                "no unique constraint or index of cities is on exactly ({0}), which \
+                 --conflict-on needs; create one, e.g. CREATE UNIQUE INDEX ON cities ({0})",
+                args.conflict_on.join(", ")
+            );
+            process::exit(1);
+        }
+        if matches!(
+            args.null_coordinate_policy,
+            NullCoordinatePolicy::NullGeometry
+        ) && !is_nullable(conn, geometry_column)
+        {
+            eprintln!(
+                "--null-coordinate-policy null-geometry needs a nullable {0} column, \
+                 make it one with ALTER TABLE cities ALTER COLUMN {0} DROP NOT NULL",
+                geometry_column
+            );
+            process::exit(1);
+        }
+        if !srid_exists(conn, args.srid) {
+            eprintln!(
+                "SRID {} is not in spatial_ref_sys, check --srid or add its definition",
+                args.srid
+            );
+            process::exit(1);
+        }
+        if args.check_country_bbox {
+            if args.srid != 4326 {
+                eprintln!("--check-country-bbox compares WGS 84 coordinates, it needs --srid 4326");
+                process::exit(2);
+            }
+            country_boxes = Some(countries::CountryBoxes::load(conn));
+        }
+        if args.async_commit {
+            // only this connection, the load run bookkeeping still commits synchronously
+            diesel::sql_query("SET synchronous_commit = off")
+                .execute(conn)
+                .expect("can't disable synchronous commit");
+            println!(
+                "warning: synchronous_commit is off, a server crash can lose the last batches \
+                 even though they were reported as inserted"
+            );
+        }
+        timings.span(
+            "migrate and check",
+            prepare_started,
+            serde_json::Value::Null,
+        );
+    }
+    // checked before the run is recorded, a checkpoint of another input ends the load
+    // without leaving it running
+    let checkpoint = args
+        .checkpoint_file
+        .as_deref()
+        .and_then(|path| read_checkpoint(path, &sha256));
+    if let Some(rows) = checkpoint {
+        println!("continuing after the {} cities of the checkpoint", rows);
+    }
+    let run = match target {
+        UploadTarget::Database(c_pool) => {
+            load_runs::LoadRunGuard::start(c_pool, &args.csv.input.to_string_lossy(), &sha256)
+        }
+        UploadTarget::SqlFile(_) => load_runs::LoadRunGuard::detached(),
+    };
+    let batch_size = args.batch_size as usize;
+    if matches!(args.insert_mode, InsertMode::Values) && batch_size > MAX_VALUES_ROWS {
+        println!(
+            "batch size {} exceeds the bind parameter limit, \
+             batches are inserted in statements of {} rows",
+            batch_size, MAX_VALUES_ROWS
+        );
+    }
+    let mut cities = Vec::with_capacity(batch_size);
+    let mut batch_counter = args.continue_from_batch;
+    // rows are parsed and deduplicated as in the original run to count the same cities
+    let resume_rows = checkpoint.unwrap_or(args.continue_from_batch * batch_size);
+    let mut resume_skip = resume_rows;
+    let checkpoint_every = args.checkpoint_every_secs.map(Duration::from_secs);
+    let mut last_checkpoint = SystemTime::now();
+    let mut inserted = 0;
+    let mut truncated = false;
+    let mut skipped = 0;
+    // unparsable rows, the first of them are kept for the --max-errors summary
+    let mut errors = 0;
+    let mut first_errors = Vec::new();
+    let mut last_position = None;
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    let mut already_present = 0;
+    let mut report = report::ValidationReport::default();
+    let mut sampler = StdRng::seed_from_u64(args.seed);
+    let mut sampled_out = 0;
+    let mut other_countries = 0;
+    let mut outside_country_bbox = 0;
+    let mut index_schedule = IndexSchedule::new(args, geometry_column);
+    // csv lines start after the discarded preamble
+    let input_line = |line: u64| line + args.csv.skip_header_rows as u64;
+
+    let open_started = Instant::now();
+    let (reader, input_end) = tracked_csv_reader(&args.csv);
+    let mut records = reader.into_records().peekable();
+    let mapping = match detect_columns(&mut records, &args.csv) {
+        Ok(mapping) => mapping,
+        Err(e) => {
+            eprintln!("can't map input columns: {}", e);
+            run.finish(0, load_runs::FAILED);
+            process::exit(1);
+        }
+    };
+    // includes extracting a .zip input
+    timings.span("open input", open_started, serde_json::Value::Null);
+    println!("{}", mapping);
+    let mut records = rows::ParsedRows::new(
+        records,
+        mapping.fields.clone(),
+        args.csv.null_tokens.clone(),
+        args.csv.empty_region_as,
+        args.jobs as usize,
+    )
+    .peekable();
+    let extra_columns = match extra_point_columns(&mapping, &args.extra_points) {
+        Ok(columns) => columns,
+        Err(e) => {
+            eprintln!("can't map extra points: {}", e);
+            run.finish(0, load_runs::FAILED);
+            process::exit(1);
+        }
+    };
+    // additional points of the cities of the batch, by index in the batch
+    let mut points = Vec::with_capacity(batch_size);
+    let mut rows_read = 0;
+    let mut batch_started = Instant::now();
+    while let Some(parsed) = records.next() {
+        rows_read += 1;
+        // handled after the loop, which checks the ratio again
+        if rows_read % batch_size == 0 && skip_ratio_exceeded(args, skipped, rows_read) {
+            break;
+        }
+        let (row, record) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) if is_truncation(&e, records.peek().is_none(), &input_end) => {
+                eprintln!(
+                    "input is truncated after {} complete rows: {}",
+                    inserted + cities.len(),
+                    describe_csv_error(&e, last_position.as_ref())
+                );
+                report.record(
+                    report::TRUNCATED_INPUT,
+                    parse_error_line(&e, last_position.as_ref()).map(input_line),
+                );
+                truncated = true;
+                break;
+            }
+            // a failed read isn't a row which can be skipped
+            Err(e) if matches!(e.kind(), csv::ErrorKind::Io(_)) => panic!(
+                "can't read input: {}",
+                describe_csv_error(&e, last_position.as_ref())
+            ),
+            Err(e) if args.continue_on_error => {
+                let error = describe_csv_error(&e, last_position.as_ref());
+                eprintln!("skipping row: {}", error);
+                report.record(
+                    report::UNPARSABLE_ROW,
+                    parse_error_line(&e, last_position.as_ref()).map(input_line),
+                );
+                skipped += 1;
+                if record_error(&mut errors, &mut first_errors, error, args) {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => panic!(
+                "can't parse city record: {}",
+                describe_csv_error(&e, last_position.as_ref())
+            ),
+        };
+        last_position = row.position().cloned();
+        if !args.only_countries.is_empty()
+            && !args
+                .only_countries
+                .iter()
+                .any(|c| c.trim().eq_ignore_ascii_case(record.country.trim()))
+        {
+            other_countries += 1;
+            continue;
+        }
+        if args.sample_rate.is_some_and(|rate| !sampler.gen_bool(rate)) {
+            sampled_out += 1;
+            continue;
+        }
+        let build_start = SystemTime::now();
+        let mut city = NewCity::from(record);
+        match (&mut city.location, args.null_coordinate_policy) {
+            // the location stays in 4326, the insert transforms it from the source SRID
+            (Some(_), _) => city.srid = Some(args.srid as i32),
+            (None, NullCoordinatePolicy::Skip) => {
+                report.record(
+                    report::NULL_COORDINATES,
+                    last_position.as_ref().map(|p| input_line(p.line())),
+                );
+                skipped += 1;
+                continue;
+            }
+            (None, NullCoordinatePolicy::Error) => {
+                run.finish(inserted, load_runs::FAILED);
+                eprintln!(
+                    "row at line {} has no coordinates, see --null-coordinate-policy",
+                    last_position.as_ref().map_or(0, |p| input_line(p.line()))
+                );
+                process::exit(1);
+            }
+            (None, NullCoordinatePolicy::NullGeometry) => {}
+        }
+        let city_points = match extra_points(&row, &extra_columns, args.srid) {
+            Ok(city_points) => city_points,
+            Err(e) if args.continue_on_error => {
+                let error = format!(
+                    "line {}: {}",
+                    last_position.as_ref().map_or(0, |p| input_line(p.line())),
+                    e
+                );
+                eprintln!("skipping row at {}", error);
+                report.record(
+                    report::UNPARSABLE_ROW,
+                    last_position.as_ref().map(|p| input_line(p.line())),
+                );
+                skipped += 1;
+                if record_error(&mut errors, &mut first_errors, error, args) {
+                    break;
+                }
+                continue;
+            }
+            Err(e) => panic!(
+                "can't parse city record at line {}: {}",
+                last_position.as_ref().map_or(0, |p| input_line(p.line())),
+                e
+            ),
+        };
+        if args.keep_raw {
+            city.raw = Some(raw_json(mapping.raw_names(), &row));
+        }
+        if args.csv.trim {
+            city.normalize_whitespace();
+        }
+        if args.with_display_name {
+            city.display_name = Some(city.coalesced_name());
+        }
+        if !args.with_latlon {
+            city.latitude = None;
+            city.longitude = None;
+        }
+        timings.build += build_start.elapsed().unwrap_or_default();
+        if args.dedupe_input && !seen.insert(city.dedupe_key(args.dedupe_precision)) {
+            duplicates += 1;
+            report.record(
+                report::DUPLICATE_ROW,
+                last_position.as_ref().map(|p| input_line(p.line())),
+            );
+            continue;
+        }
+        if resume_skip > 0 {
+            resume_skip -= 1;
+            continue;
+        }
+        if let (Some(boxes), Some(location)) = (&country_boxes, &city.location) {
+            if boxes.is_far_outside(
+                &city.country,
+                location.y,
+                location.x,
+                args.country_bbox_margin,
+            ) {
+                outside_country_bbox += 1;
+                report.record(
+                    report::OUTSIDE_COUNTRY_BBOX,
+                    last_position.as_ref().map(|p| input_line(p.line())),
+                );
+            }
+        }
+        cities.push(city);
+        points.push(city_points);
+        let checkpoint_due = checkpoint_every
+            .is_some_and(|every| last_checkpoint.elapsed().unwrap_or_default() >= every);
+        if cities.len() == batch_size || checkpoint_due {
+            println!("inserting {} batch", batch_counter);
+            timings.span(
+                "read rows",
+                batch_started,
+                serde_json::json!({ "batch": batch_counter }),
+            );
+            if args.skip_existing {
+                already_present += remove_existing(&mut sink, &mut cities, &mut points);
+            }
+            if !cities.is_empty() {
+                index_schedule.before_batch(&mut sink);
+                inserted +=
+                    timings.insert_batch(&mut sink, &cities, &points, args, geometry_column);
+                index_schedule.after_batch(&mut sink, &mut timings);
+            }
+            batch_started = Instant::now();
+            cities.clear();
+            points.clear();
+            batch_counter += 1;
+            if let Some(path) = &args.checkpoint_file {
+                write_checkpoint(path, &sha256, resume_rows + inserted + already_present);
+                last_checkpoint = SystemTime::now();
+            }
+        }
+    }
+    if let Some(path) = &args.report_file {
+        report.write(path);
+    }
+    if max_errors_exceeded(args, errors) {
+        run.finish(inserted, load_runs::FAILED);
+        eprintln!(
+            "aborted after {} unparsable rows, more than --max-errors allows, the first were:",
+            errors
+        );
+        for error in &first_errors {
+            eprintln!("  {}", error);
+        }
+        process::exit(1);
+    }
+    if skip_ratio_exceeded(args, skipped, rows_read) {
+        run.finish(inserted, load_runs::FAILED);
+        eprintln!(
+            "skipped {} of {} rows read ({:.1}%), more than --max-skip-ratio allows, \
+             the input or its column mapping is probably wrong",
+            skipped,
+            rows_read,
+            skipped as f64 / rows_read as f64 * 100.0
+        );
+        process::exit(1);
+    }
+    if resume_skip > 0 {
+        run.finish(0, load_runs::FAILED);
+        eprintln!(
+            "input ends before batch {}, check --continue-from-batch and --batch-size",
+            args.continue_from_batch
+        );
+        process::exit(1);
+    }
+    if truncated && !args.csv.allow_truncated {
+        run.finish(inserted, load_runs::FAILED);
+        eprintln!(
+            "{} rows were inserted before the truncation point, \
+             pass --allow-truncated to load the remaining {} complete rows",
+            inserted,
+            cities.len()
+        );
+        process::exit(1);
+    }
+    // numbered like the batches of the loop, which count up after their insert
+    if !cities.is_empty() {
+        println!("inserting {} batch", batch_counter);
+        timings.span(
+            "read rows",
+            batch_started,
+            serde_json::json!({ "batch": batch_counter }),
+        );
+        if args.skip_existing {
+            already_present += remove_existing(&mut sink, &mut cities, &mut points);
+        }
+        if !cities.is_empty() {
+            index_schedule.before_batch(&mut sink);
+            inserted += timings.insert_batch(&mut sink, &cities, &points, args, geometry_column);
+        }
+        if let Some(path) = &args.checkpoint_file {
+            write_checkpoint(path, &sha256, resume_rows + inserted + already_present);
+        }
+    }
+    index_schedule.finish(&mut sink, &mut timings);
+    // a file which is in the table completely isn't empty
+    if inserted + already_present == 0 && !args.allow_empty {
+        run.finish(inserted, load_runs::FAILED);
+        eprintln!(
+            "no rows found in {}, pass --allow-empty if this is expected",
+            args.csv.input.display()
+        );
+        process::exit(1);
+    }
+    if let Sink::Sql(out) = &mut sink {
+        out.flush().expect("can't write SQL script");
+    }
+    if let (Sink::Database(conn), Some(precision)) = (&mut sink, args.geohash_precision) {
+        let geohash_started = Instant::now();
+        let updated = backfill_geohash(conn, precision, false, geometry_column);
+        timings.span(
+            "geohash",
+            geohash_started,
+            serde_json::json!({ "rows": updated }),
+        );
+        println!("computed geohash of {} cities", updated);
+    }
+    if let (Sink::Database(conn), true) = (&mut sink, args.compute_rank) {
+        let rank_started = Instant::now();
+        let updated = backfill_rank(conn, &args.rank_expression);
+        timings.span("rank", rank_started, serde_json::json!({ "rows": updated }));
+        println!("computed rank of {} cities", updated);
+    }
+    run.finish(inserted, load_runs::SUCCEEDED);
+    // a .zip read from a pipe is streamed, nothing was extracted
+    if args.cleanup_extracted && !pipe {
+        remove_extracted(&args.csv.input);
+    }
+    if let Some(path) = &args.checkpoint_file {
+        if path.exists() {
+            fs::remove_file(path)
+                .unwrap_or_else(|e| panic!("can't remove checkpoint {}: {}", path.display(), e));
+        }
+    }
+    let elapsed = start.elapsed().unwrap();
+    println!(
+        "inserted {} cities, skipped {} rows in {:?}",
+        inserted, skipped, elapsed
+    );
+    if args.dedupe_input {
+        println!("skipped {} duplicate rows", duplicates);
+    }
+    if args.skip_existing {
+        println!("skipped {} rows already in the table", already_present);
+    }
+    if args.sample_rate.is_some() {
+        println!("left out {} rows by --sample-rate", sampled_out);
+    }
+    if !args.only_countries.is_empty() {
+        println!("left out {} rows of other countries", other_countries);
+    }
+    if args.check_country_bbox {
+        println!(
+            "{} cities lie more than {} degrees outside the bounding box of their country",
+            outside_country_bbox, args.country_bbox_margin
+        );
+    }
+    if args.timings {
+        timings.print(elapsed);
+    }
+    if let Some(path) = &args.metrics_file {
+        write_metrics(path, inserted, skipped, elapsed);
+    }
+    timings.span(
+        "upload",
+        upload_started,
+        serde_json::json!({ "rows": inserted }),
+    );
+    if let (Some(path), Some(profile)) = (&args.profile, &timings.profile) {
+        profile.write(path);
+    }
+}
+
+/// Time spent in the stages of an Upload, see `--timings`.
+#[derive(Default)]
+struct Timings {
+    /// `NewCity` construction including `Point::new`, raw JSON and whitespace cleanup.
+    build: Duration,
+    /// Separate EWKB encoding of the batch geometries, values mode only.
+    encode: Option<Duration>,
+    /// Insert statements, bind encoding and the database round trip.
+    insert: Duration,
+    /// Spans of --profile.
+    profile: Option<profile::Profile>,
+}
+
+impl Timings {
+    fn insert_batch(
+        &mut self,
+        sink: &mut Sink,
+        cities: &[NewCity],
+        points: &[Vec<(&str, Point)>],
+        args: &UploadArgs,
+        geometry_column: &str,
+    ) -> usize {
+        let started = Instant::now();
+        let conn = match sink {
+            Sink::Database(conn) => conn,
+            Sink::Sql(out) => {
+                let start = SystemTime::now();
+                write_insert_sql(out, cities, geometry_column, args.srid);
+                self.insert += start.elapsed().unwrap_or_default();
+                self.span(
+                    "write SQL",
+                    started,
+                    serde_json::json!({ "rows": cities.len() }),
+                );
+                return cities.len();
+            }
+        };
+        // unnest mode sends plain coordinates and the database builds the points
+        if args.timings && matches!(args.insert_mode, InsertMode::Values) {
+            let start = SystemTime::now();
+            encode_geometries(conn, cities);
+            *self.encode.get_or_insert_with(Duration::default) +=
+                start.elapsed().unwrap_or_default();
+        }
+        let start = SystemTime::now();
+        // the ids of a multi-row VALUES aren't guaranteed in the order of the rows either,
+        // batches with points to attach go through unnest, which pairs them
+        let ids = if args.conflict_on.is_empty() && points.iter().all(Vec::is_empty) {
+            insert_batch(conn, cities, args.insert_mode, geometry_column, args.srid)
+        } else {
+            insert_batch_unnest(conn, cities, geometry_column, args.srid, &args.conflict_on)
+        };
+        insert_city_points(conn, &ids, points, args.srid);
+        self.insert += start.elapsed().unwrap_or_default();
+        self.span("insert", started, serde_json::json!({ "rows": ids.len() }));
+        ids.len()
+    }
+
+    /// Records a span of --profile from `started` until now.
+    fn span(&mut self, name: &str, started: Instant, args: serde_json::Value) {
+        if let Some(profile) = &mut self.profile {
+            profile.span(name, started, args);
+        }
+    }
+
+    fn print(&self, total: Duration) {
+        let encode = self.encode.unwrap_or_default();
+        // whatever isn't measured is spent reading, decompressing and deserializing rows
+        let parse = total.saturating_sub(self.build + encode + self.insert);
+        println!(
+            "reading and parsing {:?}, building cities {:?}, inserting {:?}",
+            parse, self.build, self.insert
+        );
+        if let Some(encode) = self.encode {
+            println!(
+                "encoding the geometries alone takes {:?}, the rest of the insert is \
+                 other columns and the database round trip",
+                encode
+            );
+        }
+    }
+}
+
+/// Creates the `--sql-out` script, starting with the settings its string literals need.
+fn create_sql_script(path: &Path) -> BufWriter<File> {
+    let mut out = BufWriter::new(
+        File::create(path)
+            .unwrap_or_else(|e| panic!("can't create SQL script {}: {}", path.display(), e)),
+    );
+    writeln!(out, "SET standard_conforming_strings = on;").expect("can't write SQL script");
+    out
+}
+
+/// Quotes a value as SQL string literal, or NULL.
+fn sql_literal(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("'{}'", value.replace('\'', "''")),
+        None => "NULL".to_string(),
+    }
+}
+
+/// Appends the batch as one multi-row INSERT, with the same columns as the values mode.
+/// Coordinates in another `srid` than 4326 are transformed by the script.
+fn write_insert_sql(out: &mut impl Write, cities: &[NewCity], geometry_column: &str, srid: u32) {
+    let mut sql = format!(
+        "INSERT INTO cities ({}) VALUES\n",
+        new_city_column_list(geometry_column)
+    );
+    for (i, city) in cities.iter().enumerate() {
+        let point = city.location.as_ref().map(|l| {
+            let point = format!("ST_GeomFromEWKT('SRID={};POINT({} {})')", srid, l.x, l.y);
+            if srid == 4326 {
+                point
+            } else {
+                format!("ST_Transform({}, 4326)", point)
+            }
+        });
+        let location = point.clone().unwrap_or_else(|| "NULL".to_string());
+        // the stored coordinates are those of the location, after the transformation
+        let coordinate = |value: Option<f64>, function: &str| match (value, &point) {
+            (Some(_), Some(point)) if srid != 4326 => format!("{}({})", function, point),
+            (value, _) => value.map_or("NULL".to_string(), |v| v.to_string()),
+        };
+        let raw = match &city.raw {
+            Some(raw) => format!("{}::jsonb", sql_literal(Some(&raw.to_string()))),
+            None => "NULL".to_string(),
+        };
+        sql.push_str(&format!(
+            "({}, {}, {}, {}, {}, {}, {}, {}, {}, {}, {}){}\n",
+            sql_literal(Some(&city.country)),
+            sql_literal(Some(&city.city)),
+            sql_literal(Some(&city.accent_city)),
+            sql_literal(city.region.as_deref()),
+            location,
+            city.population
+                .map_or("NULL".to_string(), |p| p.to_string()),
+            raw,
+            sql_literal(city.display_name.as_deref()),
+            city.srid.map_or("NULL".to_string(), |s| s.to_string()),
+            coordinate(city.latitude, "ST_Y"),
+            coordinate(city.longitude, "ST_X"),
+            if i + 1 == cities.len() { ";" } else { "," }
+        ));
+    }
+    out.write_all(sql.as_bytes())
+        .expect("can't write SQL script");
+}
+
+/// Serializes the locations the way an insert sends them, without sending anything.
+fn encode_geometries(conn: &mut PgConnection, cities: &[NewCity]) {
+    let mut collector = RawBytesBindCollector::<Pg>::new();
+    for city in cities {
+        collector
+            .push_bound_value::<Nullable<Geometry>, _>(&city.location, conn)
+            .expect("can't encode geometry");
+    }
+}
+
+/// Errors kept for the summary printed when --max-errors is exceeded.
+const MAX_ERROR_SAMPLES: usize = 5;
+
+/// Counts an unparsable row and returns whether the upload has to stop for --max-errors.
+fn record_error(
+    errors: &mut usize,
+    first_errors: &mut Vec<String>,
+    error: String,
+    args: &UploadArgs,
+) -> bool {
+    *errors += 1;
+    if first_errors.len() < MAX_ERROR_SAMPLES {
+        first_errors.push(error);
+    }
+    max_errors_exceeded(args, *errors)
+}
+
+fn max_errors_exceeded(args: &UploadArgs, errors: usize) -> bool {
+    args.max_errors.is_some_and(|max| errors > max)
+}
+
+fn skip_ratio_exceeded(args: &UploadArgs, skipped: usize, rows_read: usize) -> bool {
+    args.max_skip_ratio
+        .is_some_and(|max| rows_read > 0 && skipped as f64 / rows_read as f64 > max)
+}
+
+/// Progress of an Upload, see `--checkpoint-file`.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    input_sha256: String,
+    /// Cities of the input stored so far, after deduplication and sampling.
+    rows: usize,
+}
 
-pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+/// Number of stored cities of the checkpoint, `None` if there is none yet. A checkpoint
+/// of another input ends the process.
+fn read_checkpoint(path: &Path, sha256: &str) -> Option<usize> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => panic!("can't read checkpoint {}: {}", path.display(), e),
+    };
+    let checkpoint: Checkpoint = serde_json::from_str(&content)
+        .unwrap_or_else(|e| panic!("can't parse checkpoint {}: {}", path.display(), e));
+    if checkpoint.input_sha256 != sha256 {
+        eprintln!(
+            "checkpoint {} belongs to another input, remove it to load from the start",
+            path.display()
+        );
+        process::exit(1);
+    }
+    Some(checkpoint.rows)
+}
 
-fn run_migration(conn: &mut PooledConnection<ConnectionManager<PgConnection>>) {
-    conn.run_pending_migrations(MIGRATIONS)
-        .expect("migration failure");
+/// Written next to the target and renamed like the metrics, so a crash never leaves a
+/// half written checkpoint.
+fn write_checkpoint(path: &Path, sha256: &str, rows: usize) {
+    let checkpoint = Checkpoint {
+        input_sha256: sha256.to_string(),
+        rows,
+    };
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_string(&checkpoint).unwrap())
+        .unwrap_or_else(|e| panic!("can't write checkpoint {}: {}", tmp.display(), e));
+    fs::rename(&tmp, path)
+        .unwrap_or_else(|e| panic!("can't write checkpoint {}: {}", path.display(), e));
 }
 
-fn init_connection_pool() -> Pool<ConnectionManager<PgConnection>> {
-    dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
-    let cm = ConnectionManager::new(database_url);
-    Builder::new()
-        .max_size(20)
-        .min_idle(Some(1))
-        .max_lifetime(Some(Duration::from_secs(30)))
-        .build(cm)
-        .expect("can't create connection pool!")
+/// Writes the metrics in the Prometheus text format. The file is written next to the
+/// target and renamed, so a collector never reads it half written.
+fn write_metrics(path: &Path, inserted: usize, skipped: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let metrics = [
+        (
+            "cities_loader_rows_inserted",
+            "Rows inserted by the last Upload",
+            inserted as f64,
+        ),
+        (
+            "cities_loader_rows_skipped",
+            "Invalid rows skipped by the last Upload",
+            skipped as f64,
+        ),
+        (
+            "cities_loader_load_duration_seconds",
+            "Duration of the last Upload",
+            seconds,
+        ),
+        (
+            "cities_loader_throughput_rows_per_second",
+            "Rows inserted per second by the last Upload",
+            if seconds > 0.0 {
+                inserted as f64 / seconds
+            } else {
+                0.0
+            },
+        ),
+    ];
+    let mut content = String::new();
+    for (name, help, value) in metrics {
+        content.push_str(&format!(
+            "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+        ));
+    }
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, content)
+        .unwrap_or_else(|e| panic!("can't write metrics to {}: {}", tmp.display(), e));
+    fs::rename(&tmp, path)
+        .unwrap_or_else(|e| panic!("can't write metrics to {}: {}", path.display(), e));
 }
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-struct Cli {
-    #[command(subcommand)]
-    command: Commands,
+/// Whether a parse error means the input was cut off: the input ended in the middle of
+/// a compressed stream, or its last record misses fields and no line end follows it.
+/// Any other error is one of the data, wherever it is.
+fn is_truncation(e: &csv::Error, last_record: bool, end: &InputEnd) -> bool {
+    match e.kind() {
+        csv::ErrorKind::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        csv::ErrorKind::UnequalLengths { .. } => last_record && !end.is_line_end(),
+        _ => false,
+    }
 }
 
-#[derive(Subcommand)]
-enum Commands {
-    /// Upload data to postgres DB
-    Upload,
-    /// Execute sequentially 500 requests to find 500 nearest neighbors in a loop
-    Bench,
+/// Parse errors carry the position of the bad record, for the rest (I/O, UTF-8)
+/// the last good record is the best known location.
+fn describe_csv_error(e: &csv::Error, last_position: Option<&csv::Position>) -> String {
+    match (e.position(), last_position) {
+        (None, Some(pos)) => format!(
+            "{} (after the record at line {}, byte {})",
+            e,
+            pos.line(),
+            pos.byte()
+        ),
+        _ => e.to_string(),
+    }
 }
 
-fn main() {
-    let cli = Cli::parse();
+/// Whether the last byte read from the input, once decompressed, ends a line.
+#[derive(Clone, Default)]
+struct InputEnd(Arc<AtomicBool>);
 
-    let c_pool = init_connection_pool();
-    match cli.command {
-        Commands::Upload => insert_data(&c_pool),
-        Commands::Bench => bench_get(&c_pool),
+impl InputEnd {
+    fn is_line_end(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
     }
 }
 
-fn bench_get(c_pool: &Pool<ConnectionManager<PgConnection>>) {
-    let mut rng = thread_rng();
-    let mut conn = c_pool.get().unwrap();
-    let start = SystemTime::now();
-    for _ in 0..500 {
-        cities::table
-            .order_by(postgis_diesel::operators::distance_2d(
-                cities::location,
-                Point::new(
-                    rng.gen_range(-90.0..90.0),
-                    rng.gen_range(-180.0..180.0),
-                    Some(4326),
-                ),
-            ))
-            .limit(500)
-            .execute(&mut conn)
-            .expect("nothing found");
+struct TrackedInput {
+    input: Box<dyn Read + Send>,
+    end: InputEnd,
+}
+
+impl Read for TrackedInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        if n > 0 {
+            self.end.0.store(buf[n - 1] == b'\n', Ordering::Relaxed);
+        }
+        Ok(n)
     }
-    println!("elapsed {:?}", start.elapsed())
 }
 
-fn insert_data(c_pool: &Pool<ConnectionManager<PgConnection>>) {
-    let mut conn = c_pool.get().expect("can't get connection");
-    run_migration(&mut conn);
-    let batch_size = 10000;
-    let f = File::open("./data/cities.txt.zip")
-        .expect("can't open cities file, expected location ./data/cities.txt.zip");
-    let reader = BufReader::new(f);
-    let mut zip_reader = zip::ZipArchive::new(reader).unwrap();
+/// Opens the Upload input as CSV in the dialect given by the options, past the preamble.
+fn csv_reader(args: &CsvArgs) -> csv::Reader<BufReader<Box<dyn Read + Send>>> {
+    tracked_csv_reader(args).0
+}
+
+/// `csv_reader`, with the end of the input which has been read so far.
+fn tracked_csv_reader(args: &CsvArgs) -> (csv::Reader<BufReader<Box<dyn Read + Send>>>, InputEnd) {
+    let buffer_bytes = args.read_buffer_bytes as usize;
+    let pipe = args.is_pipe();
+    // extracting needs the directory at the end of the archive, which a pipe can't seek to
+    let stream = (args.stream_zip || pipe) && args.input.extension().is_some_and(|e| e == "zip");
+    let end = InputEnd::default();
+    let mut input = BufReader::with_capacity(
+        buffer_bytes,
+        Box::new(TrackedInput {
+            input: if stream {
+                zip_stream::open(&args.input, args.allow_truncated, !pipe, buffer_bytes)
+            } else {
+                open_input(&args.input, args.allow_truncated, buffer_bytes)
+            },
+            end: end.clone(),
+        }) as Box<dyn Read + Send>,
+    );
+    let mut line = Vec::new();
+    for _ in 0..args.skip_header_rows {
+        line.clear();
+        if input
+            .read_until(b'\n', &mut line)
+            .expect("can't read input")
+            == 0
+        {
+            break;
+        }
+    }
+    let reader = csv::ReaderBuilder::new()
+        .delimiter(if args.tsv { b'\t' } else { args.delimiter })
+        .quoting(!args.tsv)
+        .quote(args.quote)
+        .escape(args.escape)
+        .flexible(args.flexible)
+        .trim(args.csv_trim.into())
+        .has_headers(false)
+        .buffer_capacity(buffer_bytes)
+        .from_reader(input);
+    (reader, end)
+}
+
+/// Takes the header row off `records` if it has one and maps its columns.
+fn detect_columns<I: Iterator<Item = csv::Result<StringRecord>>>(
+    records: &mut Peekable<I>,
+    args: &CsvArgs,
+) -> Result<columns::ColumnMapping, String> {
+    let detected = match records.peek() {
+        Some(Ok(first)) if !args.no_header => columns::ColumnMapping::detect(first),
+        _ => None,
+    };
+    match detected {
+        Some(Ok(mut mapping)) => {
+            records.next();
+            // the parser only trims headers it reads as such, which it doesn't here
+            if let (CsvTrim::Headers, Some(header)) = (args.csv_trim, &mut mapping.original) {
+                header.trim();
+            }
+            Ok(mapping)
+        }
+        Some(Err(e)) => Err(e),
+        None => {
+            if args.detect_header
+                && matches!(records.peek(), Some(Ok(first)) if !has_numeric_coordinates(first))
+            {
+                let first = records.next().unwrap().unwrap();
+                eprintln!(
+                    "first row has non-numeric coordinates, skipping it as header: {}",
+                    first.iter().collect::<Vec<_>>().join(", ")
+                );
+            }
+            Ok(columns::ColumnMapping::positional())
+        }
+    }
+}
+
+/// Whether the latitude and longitude columns of the positional layout are numbers or
+/// empty, as in a data row.
+fn has_numeric_coordinates(row: &StringRecord) -> bool {
+    ["latitude", "longitude"].iter().all(|field| {
+        let idx = columns::FIELDS.iter().position(|f| f == field).unwrap();
+        row.get(idx)
+            .map(str::trim)
+            .is_some_and(|value| value.is_empty() || value.parse::<f64>().is_ok())
+    })
+}
+
+/// Line of the broken record, or the one after the last good record if the error has none.
+fn parse_error_line(e: &csv::Error, last_position: Option<&csv::Position>) -> Option<u64> {
+    e.position()
+        .map(|p| p.line())
+        .or_else(|| last_position.map(|p| p.line() + 1))
+}
+
+/// Maps header names to the values of the row as they were read from the file.
+fn raw_json(headers: &StringRecord, row: &StringRecord) -> serde_json::Value {
+    serde_json::Value::Object(
+        headers
+            .iter()
+            .zip(row.iter())
+            .map(|(h, v)| (h.to_string(), serde_json::Value::String(v.to_string())))
+            .collect(),
+    )
+}
+
+/// Opens the input file, picking a decompressor by its extension.
+/// Compressed files are read through a buffer of `buffer_bytes`, the caller buffers the
+/// returned stream.
+fn open_input(path: &Path, allow_truncated: bool, buffer_bytes: usize) -> Box<dyn Read + Send> {
+    let f = File::open(path)
+        .unwrap_or_else(|e| panic!("can't open cities file {}: {}", path.display(), e));
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("zip") => Box::new(File::open(extract_zip(f, allow_truncated)).unwrap()),
+        Some("gz") => decompress(f, buffer_bytes, MultiGzDecoder::new),
+        Some("bz2") => decompress(f, buffer_bytes, MultiBzDecoder::new),
+        Some("xz") => decompress(f, buffer_bytes, XzDecoder::new_multi_decoder),
+        _ => Box::new(f),
+    }
+}
+
+/// A compressed file, which notes when it was read to its end.
+struct CompressedFile {
+    file: File,
+    end: FileEnd,
+}
+
+impl CompressedFile {
+    fn new(file: File) -> (Self, FileEnd) {
+        let end = FileEnd::default();
+        (
+            CompressedFile {
+                file,
+                end: end.clone(),
+            },
+            end,
+        )
+    }
+}
+
+impl Read for CompressedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.end.0.store(true, Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+/// Whether a `CompressedFile` was read to its end.
+#[derive(Clone, Default)]
+struct FileEnd(Arc<AtomicBool>);
+
+impl FileEnd {
+    fn decompressed<D>(&self, decoder: D) -> Decompressed<D> {
+        Decompressed {
+            decoder,
+            file_end: self.clone(),
+        }
+    }
+}
+
+/// The decompressed stream of a `CompressedFile`. A decoder failing once the whole file
+/// was read found it cut off, whatever it calls the error, e.g. a corrupt deflate
+/// stream, and the failure is reported as `UnexpectedEof` like the other decoders do.
+struct Decompressed<D> {
+    decoder: D,
+    file_end: FileEnd,
+}
+
+impl<D: Read> Read for Decompressed<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf).map_err(|e| {
+            if self.file_end.0.load(Ordering::Relaxed) && e.kind() != io::ErrorKind::UnexpectedEof {
+                io::Error::new(io::ErrorKind::UnexpectedEof, e)
+            } else {
+                e
+            }
+        })
+    }
+}
+
+fn decompress<D: Read + Send + 'static>(
+    file: File,
+    buffer_bytes: usize,
+    decoder: impl FnOnce(BufReader<CompressedFile>) -> D,
+) -> Box<dyn Read + Send> {
+    let (file, end) = CompressedFile::new(file);
+    Box::new(end.decompressed(decoder(BufReader::with_capacity(buffer_bytes, file))))
+}
+
+/// Extracts the archive into the current directory and returns the path of its first
+/// .txt or .csv file, wherever it's nested, or of its first file if there is none.
+///
+/// With `allow_truncated` a failed CRC check keeps the extracted data, and an archive
+/// without the central directory (cut off download) is read entry by entry from the
+/// local headers, keeping whatever part of the first entry could be decompressed.
+fn extract_zip(mut f: File, allow_truncated: bool) -> PathBuf {
     let data_path = Path::new("./");
-    zip_reader.extract(data_path).unwrap();
-    let mut rdr = csv::Reader::from_reader(BufReader::new(File::open("./data/cities.txt").unwrap()));
-    let mut cities = Vec::with_capacity(batch_size);
-    let mut batch_counter = 0;
+    let mut zip_reader = match zip::ZipArchive::new(BufReader::new(&f)) {
+        Ok(zip_reader) => zip_reader,
+        Err(e) if allow_truncated => {
+            eprintln!(
+                "zip archive is truncated ({}), recovering first data file",
+                e
+            );
+            f.rewind().unwrap();
+            return extract_zip_stream(BufReader::new(f), data_path);
+        }
+        Err(e) => panic!(
+            "zip archive is truncated or corrupt ({}), pass --allow-truncated to recover it",
+            e
+        ),
+    };
+    match zip_reader.extract(data_path) {
+        Ok(()) => {}
+        Err(e) if allow_truncated => eprintln!("zip extraction failed ({}), continuing", e),
+        Err(e) => panic!(
+            "zip extraction failed ({}), pass --allow-truncated to load the extracted data",
+            e
+        ),
+    }
+    let index = data_entry_index(&mut zip_reader).expect("zip archive has no files");
+    let entry = zip_reader.by_index(index).unwrap();
+    data_path.join(entry.enclosed_name().expect("invalid path inside zip"))
+}
 
-    for result in rdr.deserialize::<CityRecord>() {
-        cities.push(NewCity::from(result.unwrap()));
-        if cities.len() == batch_size {
-            println!("inserting {} batch", batch_counter);
-            diesel::insert_into(cities::table)
-                .values(&cities)
-                .execute(&mut conn)
-                .expect("can't insert cities");
-            cities.clear();
-            batch_counter += 1;
+/// Index of the first .txt or .csv file of the archive, wherever it's nested, or of its
+/// first file if there is none.
+fn data_entry_index<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Option<usize> {
+    // by index rather than file_names(), which isn't in archive order
+    let files: Vec<(usize, PathBuf)> = (0..archive.len())
+        .filter_map(|i| {
+            let entry = archive.by_index(i).ok()?;
+            entry
+                .is_file()
+                .then(|| entry.enclosed_name().map(|name| (i, name.to_path_buf())))?
+        })
+        .collect();
+    files
+        .iter()
+        .find(|(_, name)| is_data_file(name))
+        .or_else(|| files.first())
+        .map(|(i, _)| *i)
+}
+
+/// Whether a zip entry looks like the cities table rather than a readme or a directory.
+fn is_data_file(name: &Path) -> bool {
+    name.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("txt") || e.eq_ignore_ascii_case("csv"))
+}
+
+/// Removes the files `extract_zip` wrote for a .zip input, nothing for other inputs.
+fn remove_extracted(input: &Path) {
+    if input.extension().and_then(|e| e.to_str()) != Some("zip") {
+        return;
+    }
+    let f = File::open(input)
+        .unwrap_or_else(|e| panic!("can't open cities file {}: {}", input.display(), e));
+    // a truncated archive has no readable directory, only its first entry was extracted
+    let names: Vec<PathBuf> = match zip::ZipArchive::new(BufReader::new(f)) {
+        Ok(mut archive) => (0..archive.len())
+            .filter_map(|i| {
+                let entry = archive.by_index(i).ok()?;
+                entry
+                    .is_file()
+                    .then(|| entry.enclosed_name().map(Path::to_path_buf))?
+            })
+            .collect(),
+        Err(_) => {
+            let mut f = BufReader::new(File::open(input).unwrap());
+            with_stream_data_file(&mut f, |entry| entry.enclosed_name().map(Path::to_path_buf))
+                .flatten()
+                .into_iter()
+                .collect()
+        }
+    };
+    for name in names {
+        let path = Path::new("./").join(name);
+        if path.exists() {
+            fs::remove_file(&path)
+                .unwrap_or_else(|e| panic!("can't remove {}: {}", path.display(), e));
+            println!("removed extracted {}", path.display());
         }
     }
-    if !cities.is_empty() {
-        batch_counter += 1;
-        println!("inserting {} batch", batch_counter);
-        diesel::insert_into(cities::table)
-            .values(&cities)
-            .execute(&mut conn)
-            .expect("can't insert cities");
+}
+
+/// Calls `f` with the first .txt or .csv file entry in the local headers of the archive.
+fn with_stream_data_file<T>(
+    reader: &mut impl Read,
+    f: impl FnOnce(zip::read::ZipFile) -> T,
+) -> Option<T> {
+    loop {
+        let entry = zip::read::read_zipfile_from_stream(reader).expect("can't read zip entry")?;
+        // the entry borrows the reader, so it's handed to `f` instead of being returned
+        if entry.is_file() && entry.enclosed_name().is_some_and(is_data_file) {
+            return Some(f(entry));
+        }
+        // the rest of a skipped entry is consumed when it's dropped
+    }
+}
+
+/// Extracts the first .txt or .csv file found in the local headers, the data of an
+/// archive cut off before the central directory.
+fn extract_zip_stream(mut reader: impl Read, data_path: &Path) -> PathBuf {
+    with_stream_data_file(&mut reader, |mut entry| {
+        let path = data_path.join(entry.enclosed_name().expect("invalid path inside zip"));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        let mut out = File::create(&path).unwrap();
+        if let Err(e) = io::copy(&mut entry, &mut out) {
+            eprintln!("zip entry is incomplete: {}", e);
+        }
+        path
+    })
+    .expect("zip archive has no .txt or .csv file")
+}
+
+fn insert_batch(
+    conn: &mut PgConnection,
+    cities: &[NewCity],
+    mode: InsertMode,
+    geometry_column: &str,
+    srid: u32,
+) -> Vec<i64> {
+    match mode {
+        InsertMode::Values => cities
+            .chunks(MAX_VALUES_ROWS)
+            .flat_map(|chunk| {
+                let transformed;
+                let chunk = if srid == 4326 {
+                    chunk
+                } else {
+                    transformed = to_wgs84_cities(conn, chunk, srid);
+                    &transformed[..]
+                };
+                diesel::insert_into(cities::table)
+                    .values(chunk)
+                    .returning(cities::id)
+                    .get_results(conn)
+                    .expect("can't insert cities")
+            })
+            .collect(),
+        InsertMode::Unnest => insert_batch_unnest(conn, cities, geometry_column, srid, &[]),
+    }
+}
+
+#[derive(QueryableByName)]
+struct TransformedPoint {
+    #[diesel(sql_type = Nullable<Geometry>)]
+    location: Option<Point>,
+}
+
+/// Transforms points in `srid` to 4326 in the database, in the same order.
+fn to_wgs84(conn: &mut PgConnection, points: &[Option<Point>], srid: u32) -> Vec<Option<Point>> {
+    let query = diesel::sql_query(
+        "SELECT ST_Transform(ST_SetSRID(g, $2), 4326) AS location \
+         FROM unnest($1::geometry[]) WITH ORDINALITY AS t(g, ord) ORDER BY ord",
+    )
+    .bind::<Array<Nullable<Geometry>>, _>(points)
+    .bind::<Int4, _>(srid as i32);
+    log_query(&query);
+    query
+        .load::<TransformedPoint>(conn)
+        .unwrap_or_else(|e| panic!("can't transform coordinates from SRID {}: {}", srid, e))
+        .into_iter()
+        .map(|p| p.location)
+        .collect()
+}
+
+/// Copy of the cities with the locations, and the coordinates stored with them,
+/// transformed from `srid` to 4326.
+fn to_wgs84_cities(conn: &mut PgConnection, cities: &[NewCity], srid: u32) -> Vec<NewCity> {
+    let locations: Vec<Option<Point>> = cities.iter().map(|c| c.location).collect();
+    cities
+        .iter()
+        .zip(to_wgs84(conn, &locations, srid))
+        .map(|(city, location)| {
+            let mut city = city.clone();
+            city.location = location;
+            if city.latitude.is_some() {
+                city.latitude = location.map(|l| l.y);
+                city.longitude = location.map(|l| l.x);
+            }
+            city
+        })
+        .collect()
+}
+
+/// Stores the additional points of a batch, `ids` are those of its cities in the same
+/// order. Points in another `srid` than 4326 are transformed.
+fn insert_city_points(
+    conn: &mut PgConnection,
+    ids: &[i64],
+    points: &[Vec<(&str, Point)>],
+    srid: u32,
+) {
+    let transformed;
+    let points = if srid == 4326 || points.iter().all(Vec::is_empty) {
+        points
+    } else {
+        let flat: Vec<Option<Point>> = points.iter().flatten().map(|&(_, p)| Some(p)).collect();
+        let mut wgs84 = to_wgs84(conn, &flat, srid).into_iter();
+        transformed = points
+            .iter()
+            .map(|city| {
+                city.iter()
+                    .map(|&(kind, _)| (kind, wgs84.next().unwrap().expect("point was NULL")))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        &transformed[..]
+    };
+    let rows: Vec<NewCityPoint> = ids
+        .iter()
+        .zip(points)
+        .flat_map(|(&city_id, points)| {
+            points.iter().map(move |&(kind, location)| NewCityPoint {
+                city_id,
+                kind,
+                location,
+            })
+        })
+        .collect();
+    for chunk in rows.chunks(PG_MAX_BIND_PARAMS / NEW_CITY_POINT_COLUMNS) {
+        diesel::insert_into(city_points::table)
+            .values(chunk)
+            .execute(conn)
+            .expect("can't insert city points");
+    }
+}
+
+/// The values of the `NewCity` columns over the unnested arrays of `insert_batch_unnest`,
+/// each aliased as its column. Latitude and longitude are only sent when stored, as the
+/// coordinates of the location.
+fn unnest_projection(geometry_column: &str) -> Vec<String> {
+    let values: [&str; NEW_CITY_COLUMNS] = [
+        "country",
+        "city",
+        "accent_city",
+        "region",
+        "g",
+        "population",
+        "raw",
+        "display_name",
+        "CASE WHEN g IS NOT NULL THEN $9 END",
+        "CASE WHEN latitude IS NOT NULL THEN ST_Y(g) END",
+        "CASE WHEN longitude IS NOT NULL THEN ST_X(g) END",
+    ];
+    values
+        .iter()
+        .zip(new_city_columns(geometry_column))
+        .map(|(value, column)| format!("{} AS \"{}\"", value, column))
+        .collect()
+}
+
+/// Sends the batch as one array per column, so the statement text doesn't
+/// grow with the batch size and Postgres parses it only once. Returns the ids in the order
+/// of `cities`, which RETURNING doesn't guarantee: every row gets its id from the sequence
+/// up front and is paired with the written row by its ordinal in the arrays.
+///
+/// With `conflict_on` columns, cities matching an existing one on them update it. The
+/// ids of updated cities are returned like those of inserted ones, they are paired on the
+/// conflict columns, which are unique.
+fn insert_batch_unnest(
+    conn: &mut PgConnection,
+    cities: &[NewCity],
+    geometry_column: &str,
+    srid: u32,
+    conflict_on: &[String],
+) -> Vec<i64> {
+    let columns = new_city_columns(geometry_column);
+    let projection = unnest_projection(geometry_column);
+    let (on_conflict, returning, pairing) = if conflict_on.is_empty() {
+        (
+            String::new(),
+            String::new(),
+            "rows.id = written.id".to_string(),
+        )
+    } else {
+        let updates: Vec<String> = columns
+            .iter()
+            .filter(|c| !conflict_on.iter().any(|k| k == *c))
+            .map(|c| format!("\"{c}\" = EXCLUDED.\"{c}\""))
+            // an updated city counts as loaded again for Export --since, and its derived
+            // columns are recomputed after the load or by Backfill
+            .chain(["loaded_at = now()", "geohash = NULL", "rank = NULL"].map(str::to_string))
+            .collect();
+        let keys: Vec<String> = conflict_on.iter().map(|c| format!("\"{}\"", c)).collect();
+        let same_keys: Vec<String> = conflict_on
+            .iter()
+            .map(|c| format!("rows.\"{c}\" IS NOT DISTINCT FROM written.\"{c}\""))
+            .collect();
+        (
+            format!(
+                "ON CONFLICT ({}) DO UPDATE SET {} ",
+                keys.join(", "),
+                updates.join(", ")
+            ),
+            format!(", {}", keys.join(", ")),
+            // an updated row keeps its id, the one taken for it is left unused
+            format!(
+                "CASE WHEN written.created THEN rows.id = written.id ELSE {} END",
+                same_keys.join(" AND ")
+            ),
+        )
+    };
+    let column_list = new_city_column_list(geometry_column);
+    let written: Vec<CityId> = diesel::sql_query(format!(
+        "WITH rows AS MATERIALIZED (\
+           SELECT ord, nextval(pg_get_serial_sequence('cities', 'id')) AS id, {projection} \
+           FROM (SELECT t.*, ST_Transform(ST_SetSRID(ST_MakePoint(lon, lat), $9), 4326) AS g \
+                 FROM unnest($1::text[], $2::text[], $3::text[], $4::text[], \
+                             $5::float8[], $6::float8[], $7::int8[], $8::jsonb[], $10::text[], \
+                             $11::float8[], $12::float8[]) \
+                      WITH ORDINALITY AS t(country, city, accent_city, region, lon, lat, \
+                                           population, raw, display_name, latitude, longitude, \
+                                           ord)) AS s\
+         ), written AS (\
+           INSERT INTO cities (id, {columns}) SELECT id, {columns} FROM rows \
+           {on_conflict}RETURNING id, xmax = 0 AS created{returning}\
+         ) \
+         SELECT written.id FROM written JOIN rows ON {pairing} ORDER BY rows.ord",
+        projection = projection.join(", "),
+        columns = column_list,
+    ))
+    .bind::<Array<Text>, _>(cities.iter().map(|c| &c.country).collect::<Vec<_>>())
+    .bind::<Array<Text>, _>(cities.iter().map(|c| &c.city).collect::<Vec<_>>())
+    .bind::<Array<Text>, _>(cities.iter().map(|c| &c.accent_city).collect::<Vec<_>>())
+    .bind::<Array<Nullable<Text>>, _>(
+        cities
+            .iter()
+            .map(|c| c.region.as_deref())
+            .collect::<Vec<_>>(),
+    )
+    .bind::<Array<Nullable<Float8>>, _>(
+        cities
+            .iter()
+            .map(|c| c.location.as_ref().map(|l| l.x))
+            .collect::<Vec<_>>(),
+    )
+    .bind::<Array<Nullable<Float8>>, _>(
+        cities
+            .iter()
+            .map(|c| c.location.as_ref().map(|l| l.y))
+            .collect::<Vec<_>>(),
+    )
+    .bind::<Array<Nullable<Int8>>, _>(cities.iter().map(|c| c.population).collect::<Vec<_>>())
+    .bind::<Array<Nullable<Jsonb>>, _>(cities.iter().map(|c| c.raw.as_ref()).collect::<Vec<_>>())
+    .bind::<Int4, _>(srid as i32)
+    .bind::<Array<Nullable<Text>>, _>(
+        cities
+            .iter()
+            .map(|c| c.display_name.as_deref())
+            .collect::<Vec<_>>(),
+    )
+    .bind::<Array<Nullable<Float8>>, _>(cities.iter().map(|c| c.latitude).collect::<Vec<_>>())
+    .bind::<Array<Nullable<Float8>>, _>(cities.iter().map(|c| c.longitude).collect::<Vec<_>>())
+    .load(conn)
+    .expect("can't insert cities");
+    assert_eq!(
+        written.len(),
+        cities.len(),
+        "written cities don't match the batch"
+    );
+    written.into_iter().map(|w| w.id).collect()
+}
+
+#[derive(QueryableByName)]
+struct Ordinal {
+    #[diesel(sql_type = Int8)]
+    ord: i64,
+}
+
+/// Removes the cities of the batch whose country, city and region are in the table
+/// already, with their extra points, and returns how many. Nothing is removed when
+/// writing SQL, `--skip-existing` needs a database.
+fn remove_existing(
+    sink: &mut Sink,
+    cities: &mut Vec<NewCity>,
+    points: &mut Vec<Vec<(&str, Point)>>,
+) -> usize {
+    let Sink::Database(conn) = sink else {
+        return 0;
+    };
+    let query = diesel::sql_query(
+        "SELECT k.ord FROM unnest($1::text[], $2::text[], $3::text[]) \
+                            WITH ORDINALITY AS k(country, city, region, ord) \
+         WHERE EXISTS ( \
+             SELECT 1 FROM cities c \
+             WHERE c.country = k.country AND c.city = k.city \
+               AND (c.region = k.region OR c.region IS NULL AND k.region IS NULL) \
+         )",
+    )
+    .bind::<Array<Text>, _>(cities.iter().map(|c| &c.country).collect::<Vec<_>>())
+    .bind::<Array<Text>, _>(cities.iter().map(|c| &c.city).collect::<Vec<_>>())
+    .bind::<Array<Nullable<Text>>, _>(
+        cities
+            .iter()
+            .map(|c| c.region.as_deref())
+            .collect::<Vec<_>>(),
+    );
+    log_query(&query);
+    let existing: HashSet<usize> = query
+        .load::<Ordinal>(conn)
+        .expect("can't look up existing cities")
+        .into_iter()
+        .map(|o| o.ord as usize - 1)
+        .collect();
+    let mut i = 0;
+    cities.retain(|_| {
+        i += 1;
+        !existing.contains(&(i - 1))
+    });
+    let mut i = 0;
+    points.retain(|_| {
+        i += 1;
+        !existing.contains(&(i - 1))
+    });
+    existing.len()
+}
+
+#[derive(QueryableByName)]
+struct CityId {
+    #[diesel(sql_type = Int8)]
+    id: i64,
+}
+
+#[derive(Clone, Queryable, QueryableByName, Selectable)]
+#[diesel(table_name=cities)]
+struct City {
+    id: i64,
+    country: String,
+    city: String,
+    accent_city: String,
+    region: Option<String>,
+    location: Point,
+    population: Option<i64>,
+}
+
+impl fmt::Display for City {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let LatLon { lat, lon } = point_to_latlon(&self.location);
+        let name = if self.accent_city.is_empty() {
+            &self.city
+        } else {
+            &self.accent_city
+        };
+        write!(f, "{}, ", name)?;
+        if let Some(region) = &self.region {
+            write!(f, "{}, ", region)?;
+        }
+        write!(f, "{} ({:.4}, {:.4})", self.country, lat, lon)?;
+        if let Some(population) = self.population {
+            write!(f, " population {}", population)?;
+        }
+        Ok(())
+    }
+}
+
+/// Output row of Export, the header matches the aliases of `CityRecord`
+/// so exported files can be uploaded again.
+#[derive(Serialize)]
+struct CityRow<'a> {
+    #[serde(rename = "Country")]
+    country: &'a str,
+    #[serde(rename = "City")]
+    city: &'a str,
+    #[serde(rename = "Accent City")]
+    accent_city: &'a str,
+    #[serde(rename = "Region")]
+    region: Option<&'a str>,
+    #[serde(rename = "Latitude")]
+    latitude: f64,
+    #[serde(rename = "Longitude")]
+    longitude: f64,
+    #[serde(rename = "Population")]
+    population: Option<i64>,
+}
+
+impl<'a> From<&'a City> for CityRow<'a> {
+    fn from(c: &'a City) -> Self {
+        let LatLon { lat, lon } = point_to_latlon(&c.location);
+        CityRow {
+            country: &c.country,
+            city: &c.city,
+            accent_city: &c.accent_city,
+            region: c.region.as_deref(),
+            latitude: lat,
+            longitude: lon,
+            population: c.population,
+        }
     }
 }
 
+fn point_to_latlon(p: &Point) -> LatLon {
+    LatLon { lat: p.y, lon: p.x }
+}
+
+/// Postgres accepts at most this many bind parameters in one statement.
+const PG_MAX_BIND_PARAMS: usize = 65535;
+/// Number of fields in `NewCity`, every one of them is a bind parameter per row.
+const NEW_CITY_COLUMNS: usize = NEW_CITY_COLUMN_NAMES.len();
+/// Columns of the cities table `NewCity` fills, in the order of its fields: all but the
+/// generated id, the defaulted loaded_at and the derived geohash and rank. The raw SQL
+/// insert paths name their columns from this list instead of spelling them out, and the
+/// names come from the schema, so a renamed column breaks the build rather than one of
+/// the paths.
+const NEW_CITY_COLUMN_NAMES: [&str; 11] = [
+    cities::country::NAME,
+    cities::city::NAME,
+    cities::accent_city::NAME,
+    cities::region::NAME,
+    cities::location::NAME,
+    cities::population::NAME,
+    cities::raw::NAME,
+    cities::display_name::NAME,
+    cities::srid::NAME,
+    cities::latitude::NAME,
+    cities::longitude::NAME,
+];
+
+/// `NEW_CITY_COLUMN_NAMES` with the location replaced by --geometry-column.
+fn new_city_columns(geometry_column: &str) -> [&str; NEW_CITY_COLUMNS] {
+    NEW_CITY_COLUMN_NAMES.map(|c| {
+        if c == cities::location::NAME {
+            geometry_column
+        } else {
+            c
+        }
+    })
+}
+
+/// Quoted column list of an INSERT of `NewCity` values.
+fn new_city_column_list(geometry_column: &str) -> String {
+    new_city_columns(geometry_column)
+        .iter()
+        .map(|c| format!("\"{}\"", c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Largest number of rows fitting into one multi-row INSERT ... VALUES statement.
+const MAX_VALUES_ROWS: usize = PG_MAX_BIND_PARAMS / NEW_CITY_COLUMNS;
+/// Number of fields in `NewCityPoint`.
+const NEW_CITY_POINT_COLUMNS: usize = 3;
+
 #[derive(Insertable)]
+#[diesel(table_name=city_points)]
+struct NewCityPoint<'a> {
+    city_id: i64,
+    kind: &'a str,
+    location: Point,
+}
+
+#[derive(Clone, Insertable)]
 #[diesel(table_name=cities)]
 struct NewCity {
     country: String,
     city: String,
     accent_city: String,
-    region: String,
-    location: Point,
+    region: Option<String>,
+    /// `None` for rows without coordinates, inserted as DEFAULT, i.e. NULL.
+    location: Option<Point>,
+    population: Option<i64>,
+    raw: Option<serde_json::Value>,
+    display_name: Option<String>,
+    /// SRID of the input coordinates, kept with the row so loads with different --srid
+    /// can be told apart; the location itself is always in 4326. `None` without
+    /// coordinates.
+    srid: Option<i32>,
+    /// Coordinates of the location, `None` without coordinates or if not stored.
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+}
+
+impl NewCity {
+    /// Records the SRID of the location as the source SRID.
+    fn with_source_srid(mut self) -> Self {
+        self.srid = self
+            .location
+            .as_ref()
+            .and_then(|l| l.srid)
+            .map(|s| s as i32);
+        self
+    }
+
+    /// Copies the coordinates of the location to the latitude and longitude columns.
+    fn with_latlon(mut self) -> Self {
+        self.latitude = self.location.as_ref().map(|l| l.y);
+        self.longitude = self.location.as_ref().map(|l| l.x);
+        self
+    }
+
+    /// The accented name, or the plain one if the accented name is empty.
+    fn coalesced_name(&self) -> String {
+        if self.accent_city.is_empty() {
+            self.city.clone()
+        } else {
+            self.accent_city.clone()
+        }
+    }
+
+    fn dedupe_key(&self, precision: i32) -> (String, String, Option<String>, Option<(i64, i64)>) {
+        let scale = 10f64.powi(precision);
+        (
+            self.country.clone(),
+            self.city.clone(),
+            self.region.clone(),
+            self.location
+                .as_ref()
+                .map(|l| ((l.x * scale).round() as i64, (l.y * scale).round() as i64)),
+        )
+    }
+
+    fn normalize_whitespace(&mut self) {
+        for field in [&mut self.country, &mut self.city, &mut self.accent_city]
+            .into_iter()
+            .chain(self.region.as_mut())
+        {
+            *field = field.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+    }
 }
 
 impl From<CityRecord> for NewCity {
@@ -136,8 +5048,19 @@ impl From<CityRecord> for NewCity {
             city: cr.city,
             accent_city: cr.accent_city,
             region: cr.region,
-            location: Point::new(cr.longitude, cr.latitude, Some(4326)),
+            location: match (cr.longitude, cr.latitude) {
+                (Some(lon), Some(lat)) => Some(Point::new(lon, lat, Some(4326))),
+                _ => None,
+            },
+            population: cr.population,
+            raw: None,
+            display_name: None,
+            srid: None,
+            latitude: None,
+            longitude: None,
         }
+        .with_source_srid()
+        .with_latlon()
     }
 }
 
@@ -147,13 +5070,26 @@ impl From<&StringRecord> for NewCity {
             country: cr.get(0).unwrap().to_string(),
             city: cr.get(1).unwrap().to_string(),
             accent_city: cr.get(2).unwrap().to_string(),
-            region: cr.get(3).unwrap().to_string(),
-            location: Point::new(
-                cr.get(5).unwrap().parse().unwrap(),
-                cr.get(4).unwrap().parse().unwrap(),
-                Some(4326),
-            ),
+            region: cr
+                .get(3)
+                .filter(|r| !r.trim().is_empty())
+                .map(str::to_string),
+            location: match (
+                parse_optional(cr.get(5).unwrap()).unwrap(),
+                parse_optional(cr.get(4).unwrap()).unwrap(),
+            ) {
+                (Some(lon), Some(lat)) => Some(Point::new(lon, lat, Some(4326))),
+                _ => None,
+            },
+            population: cr.get(6).and_then(|p| parse_optional(p).unwrap()),
+            raw: None,
+            display_name: None,
+            srid: None,
+            latitude: None,
+            longitude: None,
         }
+        .with_source_srid()
+        .with_latlon()
     }
 }
 
@@ -165,23 +5101,344 @@ struct CityRecord {
     city: String,
     #[serde(alias = "Accent City")]
     accent_city: String,
-    #[serde(alias = "Region")]
-    region: String,
-    #[serde(alias = "Latitude")]
-    latitude: f64,
-    #[serde(alias = "Longitude")]
-    longitude: f64,
+    #[serde(alias = "Region", default, deserialize_with = "blank_as_none")]
+    region: Option<String>,
+    #[serde(alias = "Latitude", default, deserialize_with = "empty_as_none")]
+    latitude: Option<f64>,
+    #[serde(alias = "Longitude", default, deserialize_with = "empty_as_none")]
+    longitude: Option<f64>,
+    #[serde(alias = "Population", default, deserialize_with = "empty_as_none")]
+    population: Option<i64>,
+}
+
+/// Fields which may be NULL, where a `--null-token` is replaced by a blank cell.
+const OPTIONAL_FIELDS: [&str; 4] = ["region", "latitude", "longitude", "population"];
+
+/// Deserializes a row with the header `fields`, reading the null tokens in optional
+/// columns as blank cells. A blank region is an empty string with `EmptyRegion::Empty`,
+/// a null token in the region column stays NULL.
+fn deserialize_city(
+    row: &StringRecord,
+    fields: &StringRecord,
+    null_tokens: &[String],
+    empty_region: EmptyRegion,
+) -> csv::Result<CityRecord> {
+    let is_null_token = |(value, field): (&str, &str)| {
+        OPTIONAL_FIELDS.contains(&field) && null_tokens.iter().any(|t| t == value.trim())
+    };
+    // rows without a null token, usually all of them, are deserialized as they are
+    let mut record: CityRecord =
+        if null_tokens.is_empty() || !row.iter().zip(fields).any(is_null_token) {
+            row.deserialize(Some(fields))?
+        } else {
+            let mut cleaned: StringRecord = row
+                .iter()
+                .zip(fields)
+                .map(|cell| if is_null_token(cell) { "" } else { cell.0 })
+                .collect();
+            cleaned.set_position(row.position().cloned());
+            cleaned.deserialize(Some(fields))?
+        };
+    if empty_region == EmptyRegion::Empty && record.region.is_none() {
+        let blank = fields
+            .iter()
+            .position(|f| f == "region")
+            .and_then(|i| row.get(i))
+            .is_some_and(|value| value.trim().is_empty());
+        if blank {
+            record.region = Some(String::new());
+        }
+    }
+    Ok(record)
+}
+
+/// Blank cells of optional columns are NULL, anything else must parse.
+fn parse_optional<T: FromStr>(s: &str) -> Result<Option<T>, T::Err> {
+    let s = s.trim();
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        s.parse().map(Some)
+    }
+}
+
+/// Blank text cells are NULL, others are kept untrimmed for `--trim false`.
+fn blank_as_none<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: Option<String> = Option::deserialize(deserializer)?;
+    Ok(s.filter(|s| !s.trim().is_empty()))
+}
+
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s: Option<&str> = Option::deserialize(deserializer)?;
+    match s {
+        Some(s) => parse_optional(s).map_err(de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+sql_function! {
+    #[sql_name = "ST_DistanceSphere"]
+    fn st_distance_sphere(a: Geometry, b: Geometry) -> Float8;
+}
+
+sql_function! {
+    #[sql_name = "ST_Distance"]
+    fn st_distance(a: Geometry, b: Geometry) -> Float8;
+}
+
+sql_function! {
+    #[sql_name = "ST_MakeEnvelope"]
+    fn st_make_envelope(xmin: Float8, ymin: Float8, xmax: Float8, ymax: Float8, srid: Int4) -> Geometry;
 }
 
 table! {
     use postgis_diesel::sql_types::*;
     use diesel::sql_types::*;
     cities (id) {
+        id -> Int8,
+        country -> Text,
+        city -> Text,
+        accent_city -> Text,
+        region -> Nullable<Text>,
+        location -> Geometry,
+        population -> Nullable<Int8>,
+        raw -> Nullable<Jsonb>,
+        geohash -> Nullable<Text>,
+        display_name -> Nullable<Text>,
+        loaded_at -> Timestamptz,
+        srid -> Nullable<Int4>,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        rank -> Nullable<Float8>,
+    }
+}
+
+table! {
+    use postgis_diesel::sql_types::*;
+    use diesel::sql_types::*;
+    city_points (id) {
         id -> Int4,
+        city_id -> Int8,
+        kind -> Text,
+        location -> Geometry,
+    }
+}
+table! {
+    use postgis_diesel::sql_types::*;
+    use diesel::sql_types::*;
+    major_cities (id) {
+        id -> Int8,
         country -> Text,
         city -> Text,
         accent_city -> Text,
-        region -> Text,
+        region -> Nullable<Text>,
         location -> Geometry,
+        population -> Nullable<Int8>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city_record(row: &[&str]) -> csv::Result<CityRecord> {
+        deserialize_city(
+            &StringRecord::from(row.to_vec()),
+            &StringRecord::from(columns::FIELDS.to_vec()),
+            &[],
+            EmptyRegion::Null,
+        )
+    }
+
+    #[test]
+    fn parse_optional_reads_blank_as_none() {
+        assert_eq!(parse_optional::<i64>(""), Ok(None));
+        assert_eq!(parse_optional::<i64>("  "), Ok(None));
+        assert_eq!(parse_optional::<i64>(" 42 "), Ok(Some(42)));
+        assert!(parse_optional::<i64>("many").is_err());
+    }
+
+    #[test]
+    fn blank_population_is_null() {
+        let record = city_record(&["fr", "paris", "Paris", "A8", "48.85", "2.35", ""]).unwrap();
+        assert_eq!(record.population, None);
+        let record = city_record(&["fr", "paris", "Paris", "A8", "48.85", "2.35", " "]).unwrap();
+        assert_eq!(record.population, None);
+    }
+
+    #[test]
+    fn population_is_parsed() {
+        let record =
+            city_record(&["fr", "paris", "Paris", "A8", "48.85", "2.35", "2138551"]).unwrap();
+        assert_eq!(record.population, Some(2138551));
+        assert_eq!(record.latitude, Some(48.85));
+        assert_eq!(record.longitude, Some(2.35));
+    }
+
+    #[test]
+    fn unparsable_population_is_an_error() {
+        assert!(city_record(&["fr", "paris", "Paris", "A8", "48.85", "2.35", "many"]).is_err());
+    }
+
+    #[test]
+    fn blank_population_of_positional_row_is_null() {
+        let row = StringRecord::from(vec!["fr", "paris", "Paris", "A8", "48.85", "2.35", ""]);
+        assert_eq!(NewCity::from(&row).population, None);
+    }
+
+    /// The error of the last record of `csv`, read with an `InputEnd` which saw it.
+    fn last_record_error(csv: &'static str) -> (csv::Error, InputEnd) {
+        let end = InputEnd::default();
+        let input = TrackedInput {
+            input: Box::new(csv.as_bytes()),
+            end: end.clone(),
+        };
+        let error = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(input)
+            .into_records()
+            .last()
+            .unwrap()
+            .unwrap_err();
+        (error, end)
+    }
+
+    #[test]
+    fn short_last_record_without_line_end_is_truncation() {
+        let (error, end) = last_record_error("fr,paris,Paris\nfr,lyon");
+        assert!(is_truncation(&error, true, &end));
+    }
+
+    #[test]
+    fn short_last_record_with_line_end_is_not_truncation() {
+        let (error, end) = last_record_error("fr,paris,Paris\nfr,lyon\n");
+        assert!(!is_truncation(&error, true, &end));
+    }
+
+    #[test]
+    fn unparsable_last_record_is_not_truncation() {
+        let error = city_record(&["fr", "paris", "Paris", "A8", "48.", "x", ""]).unwrap_err();
+        assert!(!is_truncation(&error, true, &InputEnd::default()));
+    }
+
+    #[test]
+    fn decoder_failing_at_the_end_of_the_file_is_truncation() {
+        let read = |kind| {
+            let end = FileEnd::default();
+            end.0.store(true, Ordering::Relaxed);
+            let failing = io::Error::new(kind, "corrupt deflate stream");
+            let error = csv::Error::from(
+                end.decompressed(FailingRead(Some(failing)))
+                    .read(&mut [0; 8])
+                    .unwrap_err(),
+            );
+            is_truncation(&error, false, &InputEnd::default())
+        };
+        assert!(read(io::ErrorKind::InvalidInput));
+        assert!(read(io::ErrorKind::UnexpectedEof));
+    }
+
+    struct FailingRead(Option<io::Error>);
+
+    impl Read for FailingRead {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(self.0.take().unwrap())
+        }
+    }
+
+    #[test]
+    fn location_index_of_the_default_column_is_the_migrated_one() {
+        let up = include_str!("../migrations/202210221357_cities/up.sql");
+        let index = location_index(DEFAULT_GEOMETRY_COLUMN);
+        assert_eq!(index, "cities_location_idx");
+        assert!(up.contains(&format!("CREATE INDEX {}", index)));
+    }
+
+    fn paris() -> NewCity {
+        let row = StringRecord::from(vec![
+            "fr", "paris", "Paris", "A8", "48.85", "2.35", "2138551",
+        ]);
+        NewCity::from(&row)
+    }
+
+    /// The unquoted names of the first parenthesized list after `INSERT INTO`.
+    fn inserted_columns(sql: &str) -> Vec<String> {
+        let insert = &sql[sql.find("INSERT INTO").expect("no INSERT")..];
+        let start = insert.find('(').unwrap() + 1;
+        let end = start + insert[start..].find(')').unwrap();
+        insert[start..end]
+            .split(',')
+            .map(|c| c.trim().trim_matches('"').to_string())
+            .collect()
+    }
+
+    /// Splits the first row of a VALUES list into its values, at the commas outside of
+    /// parentheses and string literals.
+    fn first_row_values(sql: &str) -> Vec<String> {
+        let row = &sql[sql.find("VALUES").unwrap()..];
+        let row = &row[row.find('(').unwrap() + 1..];
+        let (mut values, mut value) = (Vec::new(), String::new());
+        let (mut depth, mut quoted) = (0, false);
+        for c in row.chars() {
+            match c {
+                '\'' => quoted = !quoted,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted && depth == 0 => break,
+                ')' if !quoted => depth -= 1,
+                ',' if !quoted && depth == 0 => {
+                    values.push(value.trim().to_string());
+                    value.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            value.push(c);
+        }
+        values.push(value.trim().to_string());
+        values
+    }
+
+    #[test]
+    fn column_names_are_the_insertable_columns() {
+        let city = paris();
+        let insert = diesel::insert_into(cities::table).values(&city);
+        let sql = diesel::debug_query::<Pg, _>(&insert).to_string();
+        assert_eq!(inserted_columns(&sql), NEW_CITY_COLUMN_NAMES);
+    }
+
+    #[test]
+    fn sql_script_inserts_the_column_names() {
+        for srid in [4326, 3857] {
+            let mut out = Vec::new();
+            write_insert_sql(&mut out, &[paris(), paris()], "geom", srid);
+            let sql = String::from_utf8(out).unwrap();
+            assert_eq!(inserted_columns(&sql), new_city_columns("geom"));
+            assert_eq!(first_row_values(&sql).len(), NEW_CITY_COLUMNS);
+        }
+    }
+
+    #[test]
+    fn unnest_projects_the_column_names() {
+        let projection = unnest_projection("geom");
+        let aliases: Vec<&str> = projection
+            .iter()
+            .map(|p| p.rsplit_once(" AS ").unwrap().1.trim_matches('"'))
+            .collect();
+        assert_eq!(aliases, new_city_columns("geom"));
+        // the columns sent as they are come from the array of the same name
+        for (value, column) in projection.iter().zip(NEW_CITY_COLUMN_NAMES) {
+            let value = value.rsplit_once(" AS ").unwrap().0;
+            if !value.contains(' ') && value != "g" {
+                assert_eq!(value, column);
+            }
+        }
     }
 }
@@ -0,0 +1,146 @@
+//! Nearest city of every point of a CSV file, e.g. to enrich user locations with the
+//! city they are in or close to.
+
+use std::{path::Path, process};
+
+use csv::StringRecord;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    sql_types::{Array, Float8, Int8},
+    PgConnection, QueryableByName, RunQueryDsl,
+};
+use serde::Serialize;
+
+use crate::{
+    is_fifo, log_query, open_input, open_output, point_to_latlon, print_output_size, zip_stream,
+    City, LatLon,
+};
+
+/// Size of the read buffers of the points file and its decompressed stream.
+const INPUT_BUFFER_BYTES: usize = 64 * 1024;
+
+#[derive(QueryableByName)]
+struct Match {
+    /// Position of the point in the batch, starting at 1.
+    #[diesel(sql_type = Int8)]
+    ord: i64,
+    #[diesel(embed)]
+    city: City,
+    #[diesel(sql_type = Float8)]
+    distance: f64,
+}
+
+#[derive(Serialize)]
+struct MatchRow<'a> {
+    input_latitude: f64,
+    input_longitude: f64,
+    city_id: i64,
+    country: &'a str,
+    city: &'a str,
+    accent_city: &'a str,
+    region: Option<&'a str>,
+    latitude: f64,
+    longitude: f64,
+    population: Option<i64>,
+    distance_meters: f64,
+}
+
+/// Writes the input points joined with their nearest city as CSV. Every batch
+/// of points is a single query, a lateral join runs one KNN index scan per point.
+pub fn run(
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+    input: &Path,
+    lat_column: &str,
+    lon_column: &str,
+    batch_size: usize,
+    geometry_column: &str,
+    output_file: Option<&Path>,
+) {
+    let mut conn = c_pool.get().expect("can't get connection");
+    // a zipped file is read while it's decompressed, a query doesn't extract anything
+    let points = if input.extension().is_some_and(|e| e == "zip") {
+        zip_stream::open(input, false, !is_fifo(input), INPUT_BUFFER_BYTES)
+    } else {
+        open_input(input, false, INPUT_BUFFER_BYTES)
+    };
+    let mut rdr = csv::Reader::from_reader(points);
+    let header = rdr.headers().expect("can't read points header").clone();
+    let lat_idx = column_index(&header, lat_column);
+    let lon_idx = column_index(&header, lon_column);
+    let sql = format!(
+        "SELECT p.ord, c.*, ST_DistanceSphere(c.location, p.geom) AS distance \
+         FROM ( \
+             SELECT ord, ST_SetSRID(ST_MakePoint(lon, lat), 4326) AS geom \
+             FROM unnest($1::float8[], $2::float8[]) WITH ORDINALITY AS u(lon, lat, ord) \
+         ) p \
+         CROSS JOIN LATERAL ( \
+             SELECT id, country, city, accent_city, region, \"{col}\" AS location, population \
+             FROM cities \
+             ORDER BY \"{col}\" <-> p.geom \
+             LIMIT 1 \
+         ) c \
+         ORDER BY p.ord",
+        col = geometry_column
+    );
+    let mut wtr = csv::Writer::from_writer(open_output(output_file));
+    let mut points: Vec<LatLon> = Vec::with_capacity(batch_size);
+    let mut records = rdr.records();
+    loop {
+        points.clear();
+        for row in records.by_ref().take(batch_size) {
+            let row = row.expect("can't read points file");
+            points.push(LatLon {
+                lat: coordinate(&row, lat_idx, lat_column),
+                lon: coordinate(&row, lon_idx, lon_column),
+            });
+        }
+        if points.is_empty() {
+            break;
+        }
+        let query = diesel::sql_query(&sql)
+            .bind::<Array<Float8>, _>(points.iter().map(|p| p.lon).collect::<Vec<_>>())
+            .bind::<Array<Float8>, _>(points.iter().map(|p| p.lat).collect::<Vec<_>>());
+        log_query(&query);
+        let matches: Vec<Match> = query.load(&mut conn).expect("can't query nearest cities");
+        for m in &matches {
+            let point = points[m.ord as usize - 1];
+            let LatLon { lat, lon } = point_to_latlon(&m.city.location);
+            wtr.serialize(MatchRow {
+                input_latitude: point.lat,
+                input_longitude: point.lon,
+                city_id: m.city.id,
+                country: &m.city.country,
+                city: &m.city.city,
+                accent_city: &m.city.accent_city,
+                region: m.city.region.as_deref(),
+                latitude: lat,
+                longitude: lon,
+                population: m.city.population,
+                distance_meters: m.distance,
+            })
+            .expect("can't write match");
+        }
+    }
+    wtr.flush().unwrap();
+    drop(wtr);
+    print_output_size(output_file);
+}
+
+fn column_index(header: &StringRecord, name: &str) -> usize {
+    header
+        .iter()
+        .position(|column| column.trim() == name)
+        .unwrap_or_else(|| {
+            eprintln!("points file has no column \"{}\"", name);
+            process::exit(1);
+        })
+}
+
+fn coordinate(row: &StringRecord, idx: usize, name: &str) -> f64 {
+    let line = row.position().map_or(0, |p| p.line());
+    let value = row.get(idx).unwrap_or("").trim();
+    value.parse().unwrap_or_else(|_| {
+        eprintln!("line {}: {} \"{}\" is not a number", line, name, value);
+        process::exit(1);
+    })
+}
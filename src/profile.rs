@@ -0,0 +1,48 @@
+//! Trace of the stages of an Upload in the Chrome trace event format, see `--profile`.
+//! chrome://tracing, Perfetto and speedscope show it as a flame chart.
+
+use std::{fs::File, io::BufWriter, path::Path, time::Instant};
+
+use serde_json::{json, Value};
+
+/// Completed spans, all on one track since the stages run one after the other on the
+/// main thread.
+pub struct Profile {
+    origin: Instant,
+    events: Vec<Value>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            origin: Instant::now(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Records a span from `started` until now.
+    pub fn span(&mut self, name: &str, started: Instant, args: Value) {
+        let start = started.saturating_duration_since(self.origin);
+        self.events.push(json!({
+            "name": name,
+            "cat": "upload",
+            "ph": "X",
+            "ts": start.as_micros() as u64,
+            "dur": started.elapsed().as_micros() as u64,
+            "pid": 1,
+            "tid": 1,
+            "args": args,
+        }));
+    }
+
+    pub fn write(&self, path: &Path) {
+        let f = File::create(path)
+            .unwrap_or_else(|e| panic!("can't create profile {}: {}", path.display(), e));
+        serde_json::to_writer(
+            BufWriter::new(f),
+            &json!({ "traceEvents": self.events, "displayTimeUnit": "ms" }),
+        )
+        .expect("can't write profile");
+        println!("wrote {} spans to {}", self.events.len(), path.display());
+    }
+}
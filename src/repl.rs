@@ -0,0 +1,204 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, Write},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+};
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    PgConnection,
+};
+
+use crate::{
+    cities_in_bbox, count_cities, distance::DistanceImpl, format_distance, nearest,
+    point_to_latlon, shutdown, LatLon, NearestOptions, Neighbor, Units,
+};
+
+const HELP: &str = "commands:
+  near LAT LON [LIMIT]                          nearest cities, 10 by default
+  count [COUNTRY]                               number of cities, optionally of one country
+  bbox MIN_LAT MIN_LON MAX_LAT MAX_LON [LIMIT]  cities inside of the box, 100 by default
+  cache                                         hit rate of the --cache-size cache
+  help                                          print this message
+  quit, exit                                    leave the repl, same as Ctrl-D";
+
+/// Coordinates are rounded to this many decimal places for the cache key, about a meter.
+const CACHE_KEY_DECIMALS: i32 = 5;
+
+/// Latitude, longitude, both in units of the key precision, and limit.
+type CacheKey = (i64, i64, i64);
+
+/// Least recently used cache of nearest neighbor results. Cached results don't see
+/// later changes of the table, and points sharing a key get the results of the first
+/// one, with its distances unless --distance-impl recomputes them.
+struct NearestCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, Vec<Neighbor>>,
+    /// Keys from least to most recently used.
+    order: VecDeque<CacheKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl NearestCache {
+    fn new(capacity: usize) -> Self {
+        NearestCache {
+            capacity,
+            entries: HashMap::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get_or_query(
+        &mut self,
+        point: LatLon,
+        limit: i64,
+        query: impl FnOnce() -> Vec<Neighbor>,
+    ) -> Vec<Neighbor> {
+        if self.capacity == 0 {
+            return query();
+        }
+        let scale = 10f64.powi(CACHE_KEY_DECIMALS);
+        let key = (
+            (point.lat * scale).round() as i64,
+            (point.lon * scale).round() as i64,
+            limit,
+        );
+        // linear in the capacity, which is fine for the few hundred entries a session uses
+        if let Some(pos) = self.order.iter().position(|k| *k == key) {
+            self.hits += 1;
+            self.order.remove(pos);
+            self.order.push_back(key);
+            return self.entries[&key].clone();
+        }
+        self.misses += 1;
+        let neighbors = query();
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.entries.insert(key, neighbors.clone());
+        neighbors
+    }
+
+    fn print_stats(&self) {
+        let lookups = self.hits + self.misses;
+        if self.capacity == 0 {
+            println!("cache disabled, see --cache-size");
+        } else if lookups == 0 {
+            println!("no lookups yet");
+        } else {
+            println!(
+                "{} hits, {} misses, hit rate {:.1}%, {} of {} entries used",
+                self.hits,
+                self.misses,
+                self.hits as f64 / lookups as f64 * 100.0,
+                self.entries.len(),
+                self.capacity
+            );
+        }
+    }
+}
+
+/// Reads commands from stdin until `quit`, end of input, SIGTERM or SIGINT, reusing one
+/// connection. A signal during a query ends the session once the query is done, then the
+/// connections of the pool are closed.
+pub fn run(
+    c_pool: Pool<ConnectionManager<PgConnection>>,
+    units: Units,
+    cache_size: usize,
+    distance_impl: DistanceImpl,
+) {
+    let client_distance = distance_impl.client();
+    let mut conn = c_pool.get().expect("can't get connection");
+    let mut cache = NearestCache::new(cache_size);
+    shutdown::install();
+    // std retries a read interrupted by a signal, so stdin is read on its own thread and
+    // the session waits for lines or a shutdown request
+    let (line_tx, lines) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            if line_tx.send(line.expect("can't read stdin")).is_err() {
+                break;
+            }
+        }
+    });
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let line = loop {
+            if shutdown::requested() {
+                break None;
+            }
+            match lines.recv_timeout(shutdown::POLL_INTERVAL) {
+                Ok(line) => break Some(line),
+                Err(RecvTimeoutError::Timeout) => continue,
+                // Ctrl-D
+                Err(RecvTimeoutError::Disconnected) => break None,
+            }
+        };
+        let Some(line) = line else {
+            println!();
+            break;
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => {}
+            ["quit"] | ["exit"] => break,
+            ["help"] => println!("{}", HELP),
+            ["cache"] => cache.print_stats(),
+            ["near", lat, lon, rest @ ..] => match (parse_point(lat, lon), parse_limit(rest, 10)) {
+                (Ok(point), Ok(limit)) => {
+                    let neighbors = cache.get_or_query(point, limit, || {
+                        nearest(&mut conn, point, limit, &NearestOptions::default())
+                    });
+                    for n in neighbors {
+                        let distance = client_distance.map_or(n.distance, |distance| {
+                            distance(point, point_to_latlon(&n.city.location))
+                        });
+                        println!("{}\t{}", n.city, format_distance(distance, units));
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => println!("{}", e),
+            },
+            ["count"] => println!("{}", count_cities(&mut conn, None)),
+            ["count", country] => println!("{}", count_cities(&mut conn, Some(country))),
+            ["bbox", min_lat, min_lon, max_lat, max_lon, rest @ ..] => {
+                match (
+                    parse_point(min_lat, min_lon),
+                    parse_point(max_lat, max_lon),
+                    parse_limit(rest, 100),
+                ) {
+                    (Ok(min), Ok(max), Ok(limit)) => {
+                        for city in cities_in_bbox(&mut conn, min, max, limit) {
+                            println!("{}", city);
+                        }
+                    }
+                    (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => println!("{}", e),
+                }
+            }
+            _ => println!("unknown command, type help to list commands"),
+        }
+    }
+    drop(conn);
+    drop(c_pool);
+}
+
+fn parse_point(lat: &str, lon: &str) -> Result<LatLon, String> {
+    format!("{},{}", lat, lon).parse()
+}
+
+fn parse_limit(rest: &[&str], default: i64) -> Result<i64, String> {
+    match rest {
+        [] => Ok(default),
+        [limit] => limit
+            .parse()
+            .map_err(|e| format!("bad limit \"{}\": {}", limit, e)),
+        _ => Err("too many arguments".to_string()),
+    }
+}
@@ -0,0 +1,60 @@
+use std::{collections::BTreeMap, fs::File, path::Path};
+
+use serde::Serialize;
+
+pub const UNPARSABLE_ROW: &str = "unparsable_row";
+pub const DUPLICATE_ROW: &str = "duplicate_row";
+pub const NULL_COORDINATES: &str = "null_coordinates";
+pub const TRUNCATED_INPUT: &str = "truncated_input";
+pub const OUTSIDE_COUNTRY_BBOX: &str = "outside_country_bbox";
+
+/// Number of offending line numbers kept per category.
+const MAX_SAMPLES: usize = 10;
+
+#[derive(Default, Serialize)]
+struct Problem {
+    count: u64,
+    sample_lines: Vec<u64>,
+}
+
+/// Problems found in the input of an Upload, grouped by category.
+#[derive(Default, Serialize)]
+pub struct ValidationReport {
+    problems: BTreeMap<&'static str, Problem>,
+}
+
+impl ValidationReport {
+    /// Counts a problem, `line` is the input line it was found at if known.
+    pub fn record(&mut self, category: &'static str, line: Option<u64>) {
+        let problem = self.problems.entry(category).or_default();
+        problem.count += 1;
+        if let Some(line) = line {
+            if problem.sample_lines.len() < MAX_SAMPLES {
+                problem.sample_lines.push(line);
+            }
+        }
+    }
+
+    /// Writes JSON for a .json path, CSV with one row per category otherwise.
+    pub fn write(&self, path: &Path) {
+        let f = File::create(path)
+            .unwrap_or_else(|e| panic!("can't write report to {}: {}", path.display(), e));
+        if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::to_writer_pretty(f, self).expect("can't write report");
+            return;
+        }
+        let mut wtr = csv::Writer::from_writer(f);
+        wtr.write_record(["category", "count", "sample_lines"])
+            .expect("can't write report");
+        for (category, problem) in &self.problems {
+            let lines: Vec<String> = problem.sample_lines.iter().map(u64::to_string).collect();
+            wtr.write_record([
+                category.to_string(),
+                problem.count.to_string(),
+                lines.join(" "),
+            ])
+            .expect("can't write report");
+        }
+        wtr.flush().expect("can't write report");
+    }
+}
@@ -0,0 +1,151 @@
+//! Deserialization of input rows into `CityRecord`, on worker threads if `--jobs` is more
+//! than one. The CSV is still split into records on a single thread, only the field
+//! parsing is spread over the workers.
+
+use std::{
+    collections::BTreeMap,
+    sync::{mpsc, Arc, Mutex},
+    thread, vec,
+};
+
+use csv::StringRecord;
+
+use crate::{deserialize_city, CityRecord, EmptyRegion};
+
+/// Records sent to a worker at once, so the channel overhead is small per row.
+const CHUNK_ROWS: usize = 1024;
+
+pub(crate) type ParsedRow = csv::Result<(StringRecord, CityRecord)>;
+
+fn parse(
+    row: csv::Result<StringRecord>,
+    fields: &StringRecord,
+    null_tokens: &[String],
+    empty_region: EmptyRegion,
+) -> ParsedRow {
+    let row = row?;
+    let record = deserialize_city(&row, fields, null_tokens, empty_region)?;
+    Ok((row, record))
+}
+
+/// Parsed rows in input order, whichever way they were parsed.
+pub(crate) enum ParsedRows<I> {
+    Sequential {
+        records: I,
+        fields: StringRecord,
+        null_tokens: Vec<String>,
+        empty_region: EmptyRegion,
+    },
+    Parallel {
+        results: mpsc::Receiver<(usize, Vec<ParsedRow>)>,
+        /// Chunks which arrived before an earlier one.
+        pending: BTreeMap<usize, Vec<ParsedRow>>,
+        next_chunk: usize,
+        current: vec::IntoIter<ParsedRow>,
+    },
+}
+
+impl<I> ParsedRows<I>
+where
+    I: Iterator<Item = csv::Result<StringRecord>> + Send + 'static,
+{
+    pub(crate) fn new(
+        records: I,
+        fields: StringRecord,
+        null_tokens: Vec<String>,
+        empty_region: EmptyRegion,
+        jobs: usize,
+    ) -> Self {
+        if jobs <= 1 {
+            return ParsedRows::Sequential {
+                records,
+                fields,
+                null_tokens,
+                empty_region,
+            };
+        }
+        // both channels are bounded, so reading waits when the workers or the inserts fall
+        // behind, and at most a few chunks per worker are in memory
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(jobs * 2);
+        let (result_tx, results) = mpsc::sync_channel(jobs * 2);
+        thread::spawn(move || {
+            let mut records = records;
+            for seq in 0.. {
+                let chunk: Vec<_> = records.by_ref().take(CHUNK_ROWS).collect();
+                // a failed send means the rows aren't wanted anymore
+                if chunk.is_empty() || chunk_tx.send((seq, chunk)).is_err() {
+                    break;
+                }
+            }
+        });
+        let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+        for _ in 0..jobs {
+            let chunk_rx = Arc::clone(&chunk_rx);
+            let result_tx = result_tx.clone();
+            let fields = fields.clone();
+            let null_tokens = null_tokens.clone();
+            thread::spawn(move || loop {
+                // the lock is held only while waiting for the next chunk
+                let received = chunk_rx.lock().unwrap().recv();
+                let Ok((seq, chunk)) = received else {
+                    break;
+                };
+                let parsed: Vec<_> = chunk
+                    .into_iter()
+                    .map(|row: csv::Result<StringRecord>| {
+                        parse(row, &fields, &null_tokens, empty_region)
+                    })
+                    .collect();
+                if result_tx.send((seq, parsed)).is_err() {
+                    break;
+                }
+            });
+        }
+        ParsedRows::Parallel {
+            results,
+            pending: BTreeMap::new(),
+            next_chunk: 0,
+            current: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl<I> Iterator for ParsedRows<I>
+where
+    I: Iterator<Item = csv::Result<StringRecord>>,
+{
+    type Item = ParsedRow;
+
+    fn next(&mut self) -> Option<ParsedRow> {
+        match self {
+            ParsedRows::Sequential {
+                records,
+                fields,
+                null_tokens,
+                empty_region,
+            } => records
+                .next()
+                .map(|row| parse(row, fields, null_tokens, *empty_region)),
+            ParsedRows::Parallel {
+                results,
+                pending,
+                next_chunk,
+                current,
+            } => loop {
+                if let Some(row) = current.next() {
+                    return Some(row);
+                }
+                let chunk = loop {
+                    if let Some(chunk) = pending.remove(next_chunk) {
+                        break chunk;
+                    }
+                    // all workers are gone once the input is read completely
+                    let (seq, chunk) = results.recv().ok()?;
+                    pending.insert(seq, chunk);
+                };
+                *next_chunk += 1;
+                *current = chunk.into_iter();
+            },
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Minimal HTTP server answering nearest city queries as JSON, a thread per connection
+//! on top of the connection pool. It speaks just enough HTTP/1.1 for curl, browsers and
+//! load balancer health checks: GET only, no keep-alive, no TLS.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    panic::{self, AssertUnwindSafe},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    PgConnection,
+};
+use serde_json::{json, Value};
+
+use crate::{geojson, nearest, point_to_latlon, shutdown, LatLon, NearestOptions};
+
+/// Cities returned without a limit parameter.
+const DEFAULT_LIMIT: i64 = 10;
+/// Largest limit accepted, so a single request can't read the whole table.
+const MAX_LIMIT: i64 = 1000;
+/// Longest request line accepted.
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+/// Most bytes of all headers together, so a client trickling header bytes can't grow
+/// the buffer without end.
+const MAX_HEADER_BYTES: usize = 16 * 1024;
+/// Requests in flight per connection of the pool, more are refused with 503 instead of
+/// piling up threads which wait for a connection.
+const IN_FLIGHT_PER_CONNECTION: usize = 4;
+/// Time a client gets to send its request, so an idle connection can't hold up the
+/// shutdown.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a client gets to take the response.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers requests until SIGTERM or SIGINT, then stops accepting connections, waits for
+/// the requests in flight and closes the connections of the pool.
+pub fn run(c_pool: Pool<ConnectionManager<PgConnection>>, bind: &str, port: u16) {
+    let listener = TcpListener::bind((bind, port))
+        .unwrap_or_else(|e| panic!("can't listen on {}:{}: {}", bind, port, e));
+    // std retries an accept interrupted by a signal, so the listener is polled instead
+    listener
+        .set_nonblocking(true)
+        .expect("can't configure listener");
+    shutdown::install();
+    println!("listening on http://{}:{}/nearest", bind, port);
+    let max_in_flight = c_pool.max_size() as usize * IN_FLIGHT_PER_CONNECTION;
+    let mut in_flight: Vec<JoinHandle<()>> = Vec::new();
+    while !shutdown::requested() {
+        in_flight.retain(|handle| !handle.is_finished());
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(shutdown::POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("can't accept connection: {}", e);
+                continue;
+            }
+        };
+        if in_flight.len() >= max_in_flight {
+            // answered right here, without reading the request
+            let refused = stream
+                .set_nonblocking(false)
+                .and_then(|()| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                .and_then(|()| {
+                    let (status, body) = error(
+                        "503 Service Unavailable",
+                        format!("more than {} requests in flight", max_in_flight),
+                    );
+                    write_response(&mut stream, status, &body)
+                });
+            if let Err(e) = refused {
+                eprintln!("can't refuse request: {}", e);
+            }
+            continue;
+        }
+        let c_pool = c_pool.clone();
+        in_flight.push(thread::spawn(move || {
+            if let Err(e) = handle(stream, &c_pool) {
+                eprintln!("can't answer request: {}", e);
+            }
+        }));
+    }
+    drop(listener);
+    println!(
+        "shutting down, waiting for {} requests",
+        in_flight.iter().filter(|h| !h.is_finished()).count()
+    );
+    for handle in in_flight {
+        // a panicked request was reported already
+        let _ = handle.join();
+    }
+    let connections = c_pool.state().connections;
+    drop(c_pool);
+    println!("closed {} database connections", connections);
+}
+
+fn handle(
+    mut stream: TcpStream,
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+) -> std::io::Result<()> {
+    // accepted sockets inherit non-blocking mode on some platforms
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    (&mut reader)
+        .take(MAX_REQUEST_LINE as u64)
+        .read_line(&mut request_line)?;
+    // the headers don't matter, but have to be read before the response is sent
+    let mut headers = (&mut reader).take(MAX_HEADER_BYTES as u64);
+    let mut header = String::new();
+    let complete = loop {
+        header.clear();
+        match headers.read_line(&mut header)? {
+            // the end of the input, unless it's the end of the limit
+            0 => break headers.limit() > 0,
+            // the blank line ending the headers
+            n if n <= 2 && header.ends_with('\n') => break true,
+            _ => {}
+        }
+    };
+    let (status, body) = if !complete {
+        error(
+            "431 Request Header Fields Too Large",
+            format!("headers must end within {} bytes", MAX_HEADER_BYTES),
+        )
+    } else {
+        // a failed query panics like elsewhere, the client still gets an answer
+        panic::catch_unwind(AssertUnwindSafe(|| respond(&request_line, c_pool))).unwrap_or_else(
+            |_| {
+                error(
+                    "500 Internal Server Error",
+                    "can't answer the query, see the server log".to_string(),
+                )
+            },
+        )
+    };
+    drop(reader);
+    write_response(&mut stream, status, &body)
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &Value) -> std::io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Status line and JSON body for the request line, e.g. "GET /nearest?lat=1&lon=2 HTTP/1.1".
+fn respond(
+    request_line: &str,
+    c_pool: &Pool<ConnectionManager<PgConnection>>,
+) -> (&'static str, Value) {
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return error("400 Bad Request", "malformed request line".to_string());
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    if path != "/nearest" {
+        return error(
+            "404 Not Found",
+            format!("no endpoint {}, try /nearest", path),
+        );
+    }
+    if method != "GET" {
+        return error(
+            "405 Method Not Allowed",
+            "only GET is supported".to_string(),
+        );
+    }
+    let (point, limit) = match parse_query(query) {
+        Ok(parsed) => parsed,
+        Err(e) => return error("400 Bad Request", e),
+    };
+    let mut conn = match c_pool.get() {
+        Ok(conn) => conn,
+        Err(e) => return error("503 Service Unavailable", format!("no connection: {}", e)),
+    };
+    let cities: Vec<Value> = nearest(&mut conn, point, limit, &NearestOptions::default())
+        .into_iter()
+        .map(|n| {
+            let LatLon { lat, lon } = point_to_latlon(&n.city.location);
+            let mut city = geojson::city_properties(&n.city);
+            city.insert("latitude".to_string(), json!(lat));
+            city.insert("longitude".to_string(), json!(lon));
+            city.insert("distance_meters".to_string(), json!(n.distance));
+            Value::Object(city)
+        })
+        .collect();
+    ("200 OK", Value::Array(cities))
+}
+
+fn error(status: &'static str, message: String) -> (&'static str, Value) {
+    (status, json!({ "error": message }))
+}
+
+/// Point and limit of "lat=..&lon=..&limit=..", other parameters are ignored.
+fn parse_query(query: &str) -> Result<(LatLon, i64), String> {
+    let mut lat = None;
+    let mut lon = None;
+    let mut limit = DEFAULT_LIMIT;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "lat" => lat = Some(value),
+            "lon" => lon = Some(value),
+            "limit" => {
+                limit = match value.parse() {
+                    Ok(l) if (1..=MAX_LIMIT).contains(&l) => l,
+                    _ => {
+                        return Err(format!(
+                            "limit must be 1 to {}, got \"{}\"",
+                            MAX_LIMIT, value
+                        ))
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let (Some(lat), Some(lon)) = (lat, lon) else {
+        return Err("lat and lon are required".to_string());
+    };
+    let point = format!("{},{}", lat, lon).parse()?;
+    Ok((point, limit))
+}
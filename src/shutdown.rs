@@ -0,0 +1,37 @@
+//! SIGTERM and SIGINT handling of the long running Serve and Repl, which finish the
+//! work in flight and close their connections instead of being killed mid-query.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// How often blocking loops check for a shutdown request.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Replaces the default action of SIGTERM and SIGINT, which ends the process at once, by
+/// setting the flag of `requested`.
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn handle(_signal: libc::c_int) {
+        // only async-signal-safe work here
+        REQUESTED.store(true, Ordering::SeqCst);
+    }
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        // SAFETY: the handler only stores to an atomic
+        let previous = unsafe { libc::signal(signal, handle as *const () as libc::sighandler_t) };
+        if previous == libc::SIG_ERR {
+            panic!("can't install handler of signal {}", signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Whether a signal asked the process to finish.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
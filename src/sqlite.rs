@@ -0,0 +1,190 @@
+//! Upload and Nearest against SQLite with the SpatiaLite extension, to run the load
+//! pipeline without a PostGIS server. Only the CSV dialect, column and row cleanup
+//! options of Upload apply, load runs, deduplication and the other Postgres features
+//! don't exist here.
+
+use std::{path::Path, process};
+
+use postgis_diesel::types::Point;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{
+    csv_reader, describe_csv_error, deserialize_city, detect_columns, format_distance, City,
+    NearestArgs, NewCity, NullCoordinatePolicy, UploadArgs,
+};
+
+/// Opens the database, loads SpatiaLite and creates the cities table if it's missing.
+fn open(path: &Path) -> Connection {
+    let conn = Connection::open(path)
+        .unwrap_or_else(|e| panic!("can't open sqlite database {}: {}", path.display(), e));
+    // SAFETY: only SpatiaLite is loaded and extension loading is switched off right after,
+    // so queries can't load anything themselves
+    unsafe {
+        conn.load_extension_enable()
+            .expect("can't enable sqlite extensions");
+        conn.load_extension("mod_spatialite", None)
+            .expect("can't load mod_spatialite, is SpatiaLite installed?");
+    }
+    conn.load_extension_disable()
+        .expect("can't disable sqlite extensions");
+    let has_table = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'cities'",
+            [],
+            |_| Ok(()),
+        )
+        .optional()
+        .expect("can't read sqlite schema")
+        .is_some();
+    if !has_table {
+        // same layout as the Postgres migrations, the geometry column has to be added
+        // through SpatiaLite to be registered in its metadata
+        conn.execute_batch(
+            "SELECT InitSpatialMetadata(1);
+             CREATE TABLE cities (
+                 id INTEGER PRIMARY KEY,
+                 country TEXT NOT NULL,
+                 city TEXT NOT NULL,
+                 accent_city TEXT NOT NULL,
+                 region TEXT,
+                 population INTEGER);
+             SELECT AddGeometryColumn('cities', 'location', 4326, 'POINT', 'XY', 1);
+             SELECT CreateSpatialIndex('cities', 'location');",
+        )
+        .expect("can't create sqlite schema");
+    }
+    conn
+}
+
+pub fn upload(path: &Path, args: &UploadArgs) {
+    if matches!(
+        args.null_coordinate_policy,
+        NullCoordinatePolicy::NullGeometry
+    ) {
+        // AddGeometryColumn above creates the column NOT NULL
+        eprintln!("--null-coordinate-policy null-geometry isn't supported by --backend sqlite");
+        process::exit(2);
+    }
+    let mut conn = open(path);
+    let mut rdr = csv_reader(&args.csv);
+    let mut records = rdr.records().peekable();
+    let mapping = detect_columns(&mut records, &args.csv).unwrap_or_else(|e| {
+        eprintln!("can't map input columns: {}", e);
+        process::exit(1);
+    });
+    println!("{}", mapping);
+    let mut inserted = 0;
+    let mut skipped = 0;
+    let mut last_position = None;
+    let mut tx = conn.transaction().expect("can't start transaction");
+    for result in records {
+        let record = result.and_then(|row| {
+            last_position = row.position().cloned();
+            deserialize_city(
+                &row,
+                &mapping.fields,
+                &args.csv.null_tokens,
+                args.csv.empty_region_as,
+            )
+        });
+        let mut city = match record {
+            Ok(record) => NewCity::from(record),
+            Err(e) if args.continue_on_error => {
+                eprintln!(
+                    "skipping row: {}",
+                    describe_csv_error(&e, last_position.as_ref())
+                );
+                skipped += 1;
+                continue;
+            }
+            Err(e) => panic!(
+                "can't parse city record: {}",
+                describe_csv_error(&e, last_position.as_ref())
+            ),
+        };
+        if city.location.is_none() {
+            match args.null_coordinate_policy {
+                NullCoordinatePolicy::Skip => {
+                    skipped += 1;
+                    continue;
+                }
+                NullCoordinatePolicy::Error => {
+                    eprintln!(
+                        "row at line {} has no coordinates, see --null-coordinate-policy",
+                        last_position.as_ref().map_or(0, |p| p.line())
+                    );
+                    process::exit(1);
+                }
+                NullCoordinatePolicy::NullGeometry => unreachable!(),
+            }
+        }
+        if args.csv.trim {
+            city.normalize_whitespace();
+        }
+        tx.execute(
+            "INSERT INTO cities (country, city, accent_city, region, location, population) \
+             VALUES (?1, ?2, ?3, ?4, MakePoint(?5, ?6, 4326), ?7)",
+            params![
+                city.country,
+                city.city,
+                city.accent_city,
+                city.region,
+                city.location.as_ref().map(|l| l.x),
+                city.location.as_ref().map(|l| l.y),
+                city.population
+            ],
+        )
+        .expect("can't insert city");
+        inserted += 1;
+        if inserted % args.batch_size as usize == 0 {
+            tx.commit().expect("can't commit batch");
+            println!("inserted {} cities", inserted);
+            tx = conn.transaction().expect("can't start transaction");
+        }
+    }
+    tx.commit().expect("can't commit batch");
+    println!("inserted {} cities, skipped {} rows", inserted, skipped);
+}
+
+/// Nearest cities by ellipsoid distance. SpatiaLite has no KNN ordering operator like
+/// `<->`, so this scans the whole table, which is fine for test data sets.
+pub fn nearest(path: &Path, args: &NearestArgs) {
+    let conn = open(path);
+    let mut stmt = conn
+        .prepare(
+            "SELECT id, country, city, accent_city, region, X(location), Y(location), \
+                    population, ST_Distance(location, MakePoint(?1, ?2, 4326), 1) AS distance \
+             FROM cities \
+             WHERE ?3 IS NULL OR distance <= ?3 \
+             ORDER BY distance, id \
+             LIMIT ?4 OFFSET ?5",
+        )
+        .expect("can't prepare nearest query");
+    let rows = stmt
+        .query_map(
+            params![
+                args.lon,
+                args.lat,
+                args.max_distance,
+                args.limit,
+                args.offset
+            ],
+            |row| {
+                let city = City {
+                    id: row.get(0)?,
+                    country: row.get(1)?,
+                    city: row.get(2)?,
+                    accent_city: row.get(3)?,
+                    region: row.get(4)?,
+                    location: Point::new(row.get(5)?, row.get(6)?, Some(4326)),
+                    population: row.get(7)?,
+                };
+                Ok((city, row.get::<_, f64>(8)?))
+            },
+        )
+        .expect("can't query nearest cities");
+    for row in rows {
+        let (city, distance) = row.expect("can't read city");
+        println!("{}\t{}", city, format_distance(distance, args.units));
+    }
+}
@@ -0,0 +1,134 @@
+//! Reading the data file of a zip archive while it's decompressed, so parsing and
+//! inserting overlap with the decompression instead of waiting for a full extraction.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek},
+    path::Path,
+    sync::mpsc,
+    thread,
+};
+
+use crate::{data_entry_index, with_stream_data_file, CompressedFile};
+
+/// Decompressed chunks in flight between the threads, a few buffers per side.
+const CHANNEL_CHUNKS: usize = 4;
+
+/// Reads the chunks the decompressing thread sends, an error ends the stream like
+/// a failed read of the file would. An empty chunk marks the end of the entry.
+struct ChannelReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+        if self.pos == self.chunk.len() {
+            self.chunk = match self.chunks.recv() {
+                Ok(chunk) => chunk?,
+                // without the end marker, the thread panicked
+                Err(_) => return Err(io::Error::other("zip decompression stopped")),
+            };
+            self.pos = 0;
+            if self.chunk.is_empty() {
+                self.finished = true;
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Sends the entry in chunks of `buffer_bytes`, stopping when the reader is dropped.
+fn pump(mut entry: impl Read, buffer_bytes: usize, tx: &mpsc::SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; buffer_bytes];
+        let chunk = match entry.read(&mut chunk) {
+            Ok(0) => {
+                let _ = tx.send(Ok(Vec::new()));
+                return;
+            }
+            Ok(n) => {
+                chunk.truncate(n);
+                Ok(chunk)
+            }
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if tx.send(chunk).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Opens the first .txt or .csv file of the archive as a stream decompressed on another
+/// thread. With `allow_truncated` an archive without central directory is read from the
+/// local headers, and so is one which isn't `seekable`, e.g. a named pipe; a failed CRC
+/// check or a cut off entry shows as a read error at the point of the damage.
+pub fn open(
+    path: &Path,
+    allow_truncated: bool,
+    seekable: bool,
+    buffer_bytes: usize,
+) -> Box<dyn Read + Send> {
+    let mut f = File::open(path)
+        .unwrap_or_else(|e| panic!("can't open cities file {}: {}", path.display(), e));
+    // the directory is checked here, so a broken archive fails before loading starts; a
+    // pipe can't seek to it and is read from the local headers right away
+    let directory = seekable.then(|| zip::ZipArchive::new(BufReader::new(f.try_clone().unwrap())));
+    let archive = match directory {
+        None => None,
+        Some(Ok(mut archive)) => {
+            let index = data_entry_index(&mut archive).expect("zip archive has no files");
+            Some((archive, index))
+        }
+        Some(Err(e)) if allow_truncated => {
+            eprintln!(
+                "zip archive is truncated ({}), recovering first data file",
+                e
+            );
+            f.rewind().unwrap();
+            None
+        }
+        Some(Err(e)) => panic!(
+            "zip archive is truncated or corrupt ({}), pass --allow-truncated to recover it",
+            e
+        ),
+    };
+    let (tx, chunks) = mpsc::sync_channel(CHANNEL_CHUNKS);
+    thread::spawn(move || match archive {
+        Some((mut archive, index)) => match archive.by_index(index) {
+            Ok(entry) => pump(entry, buffer_bytes, &tx),
+            Err(e) => {
+                let _ = tx.send(Err(io::Error::other(e)));
+            }
+        },
+        None => {
+            // without a directory, a cut off archive ends in the middle of the entry
+            let (f, end) = CompressedFile::new(f);
+            let mut reader = BufReader::new(f);
+            let found = with_stream_data_file(&mut reader, |entry| {
+                pump(end.decompressed(entry), buffer_bytes, &tx)
+            });
+            if found.is_none() {
+                let _ = tx.send(Err(io::Error::other(
+                    "zip archive has no .txt or .csv file",
+                )));
+            }
+        }
+    });
+    Box::new(ChannelReader {
+        chunks,
+        chunk: Vec::new(),
+        pos: 0,
+        finished: false,
+    })
+}
@@ -0,0 +1,86 @@
+//! Upload runs of the binary which don't need a database, writing --sql-out scripts.
+
+use std::{fs, path::PathBuf, process::Command};
+
+/// A CSV of `rows` cities in a directory of its own for the test `name`.
+fn input(name: &str, rows: usize) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cities-loader-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut csv = String::from("Country,City,AccentCity,Region,Latitude,Longitude,Population\n");
+    for i in 0..rows {
+        csv.push_str(&format!("fr,city{i},City{i},A8,48.{i},2.{i},{i}\n"));
+    }
+    let path = dir.join("cities.csv");
+    fs::write(&path, csv).unwrap();
+    path
+}
+
+/// The numbers of the "inserting N batch" lines of an upload of `input`.
+fn inserted_batches(input: &PathBuf, extra_args: &[&str]) -> Vec<usize> {
+    let output = Command::new(env!("CARGO_BIN_EXE_cities-loader"))
+        .arg("upload")
+        .arg("--input")
+        .arg(input)
+        .arg("--sql-out")
+        .arg(input.with_extension("sql"))
+        .args(["--batch-size", "10"])
+        .args(extra_args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .filter_map(|l| l.strip_prefix("inserting ")?.strip_suffix(" batch"))
+        .map(|n| n.parse().unwrap())
+        .collect()
+}
+
+#[test]
+fn last_partial_batch_is_numbered_like_the_others() {
+    let input = input("partial-batch", 25);
+    assert_eq!(inserted_batches(&input, &[]), [0, 1, 2]);
+    fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn continuing_from_the_last_partial_batch_loads_it_again() {
+    let input = input("continue-partial-batch", 25);
+    assert_eq!(
+        inserted_batches(&input, &["--continue-from-batch", "2"]),
+        [2]
+    );
+    let script = fs::read_to_string(input.with_extension("sql")).unwrap();
+    assert!(script.contains("'city20'") && !script.contains("'city19'"));
+    fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn index_strategy_rebuilds_the_index_of_the_geometry_column() {
+    let input = input("index-geometry-column", 25);
+    let output = Command::new(env!("CARGO_BIN_EXE_cities-loader"))
+        .args([
+            "--geometry-column",
+            "geom",
+            "upload",
+            "--index-strategy",
+            "after",
+        ])
+        .arg("--input")
+        .arg(&input)
+        .arg("--sql-out")
+        .arg(input.with_extension("sql"))
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let script = fs::read_to_string(input.with_extension("sql")).unwrap();
+    assert!(script.contains("DROP INDEX IF EXISTS cities_geom_idx;"));
+    assert!(script
+        .contains("CREATE INDEX IF NOT EXISTS cities_geom_idx ON cities USING GIST (\"geom\");"));
+    assert!(!script.contains("cities_location_idx"));
+    fs::remove_dir_all(input.parent().unwrap()).unwrap();
+}